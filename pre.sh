@@ -4,6 +4,15 @@ table inet https.nat {
 }
 delete table inet https.nat
 table inet https.nat {
+    # 代理域名解析出的地址，由 harmony-rs 的 nftset 配置写入，可用于策略路由
+    set proxy4 {
+        type ipv4_addr; flags timeout;
+    }
+
+    set proxy6 {
+        type ipv6_addr; flags timeout;
+    }
+
     chain prerouting {
         type nat hook prerouting priority dstnat; policy accept;
         tcp dport 443 ip daddr != { 127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,169.254.0.0/16,224.0.0.0/4,240.0.0.0/4} redirect to 8443
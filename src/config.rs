@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+
+use serde::Deserialize;
+
+use crate::prelude::*;
+use crate::rules::Rules;
+use crate::utils::{ClientHelloInfo, MAX_HEADER_SIZE};
+
+/// 配置文件，json 格式，所有字段均为可选
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub dns: DnsConfig,
+    pub routes: Vec<Route>,
+    pub nftset: Option<NftSetConfig>,
+    /// 客户端使用 ECH 时的路由策略
+    pub ech: EchPolicy,
+    /// ECH 服务器的公开名称，外层 SNI 是其中之一时才按 ech 策略处理，未设置时为 ["cloudflare-ech.com"]，
+    /// 为空列表时所有带 ECH 扩展的连接都按 ech 策略处理，包括浏览器发送的 GREASE ECH
+    pub ech_public_names: Option<Vec<String>>,
+    pub http: HttpConfig,
+    pub socks: SocksConfig,
+    pub udp: UdpConfig,
+    pub upstream: UpstreamConfig,
+    /// 上游服务器组，组名 => 服务器列表，路由规则通过 group 选择
+    pub groups: HashMap<String, GroupConfig>,
+    pub learn: LearnConfig,
+    pub psl: PslConfig,
+}
+
+/// 公共后缀列表，用于把域名收缩为可注册的域名，以及检查覆盖整个公共后缀的规则
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PslConfig {
+    /// Public Suffix List 文件，例如 /usr/share/publicsuffix/public_suffix_list.dat，为空时使用内置的粗略规则
+    pub file: Option<String>,
+    /// 控制管道加入的域名是否收缩为可注册的域名，例如 a.b.example.co.uk => example.co.uk
+    pub normalize: bool,
+}
+
+/// 自动学习：按规则文件直连的域名连接失败后通过代理重试，重试成功时把域名加入代理列表
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LearnConfig {
+    pub enabled: bool,
+    /// 直连的超时时间，单位毫秒
+    pub timeout: u64,
+    /// 发送 ClientHello 后这段时间内连接被重置或者关闭视为失败，单位毫秒
+    pub within: u64,
+    /// 记录学习到的域名的文件，便于人工检查后加入规则文件
+    pub log: Option<String>,
+    /// 可信的 DNS 服务器名称，直连的地址不在它的解析结果中时视为 DNS 被污染
+    pub dns: Option<String>,
+}
+
+impl Default for LearnConfig {
+    fn default() -> Self {
+        LearnConfig { enabled: false, timeout: 3000, within: 1000, log: None, dns: None }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GroupConfig {
+    /// socks5 服务器地址
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// 连接这组服务器时依次经过的跳板代理，例如 ["socks5://10.0.0.1:1080", "http://jump.example.com:3128"]
+    #[serde(default)]
+    pub via: Vec<String>,
+}
+
+/// 从一组上游服务器中选择服务器的策略，选中的服务器连接失败时依次尝试下一个
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// 优先使用排在前面的可用服务器
+    #[default]
+    Failover,
+    /// 轮流使用每个服务器
+    RoundRobin,
+    /// 活动连接最少的服务器
+    LeastConn,
+    /// 最近一次握手延迟最低的服务器
+    Latency,
+    /// 按目标主机一致性哈希，同一个主机总是使用同一个服务器
+    Hash,
+}
+
+/// socks5 上游服务器的健康检查，服务器列表由 --proxy 参数指定
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// 检查间隔，单位秒，为 0 时不检查，不可用的服务器只在所有服务器都不可用时重新尝试
+    pub interval: u64,
+    /// 连接、握手以及连接探测目标的超时时间，单位秒
+    pub timeout: u64,
+    /// 通过代理连接的探测目标，例如 "www.gstatic.com:80"，为空时只检查 socks5 握手
+    pub probe: Option<String>,
+    /// 连接 --proxy 指定的服务器时依次经过的跳板代理
+    pub via: Vec<String>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig { interval: 30, timeout: 5, probe: None, via: Vec::new() }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct UdpConfig {
+    /// UDP 流没有数据后保留的时间，单位秒
+    pub timeout: u64,
+    /// 需要代理的 QUIC 连接的处理方式
+    pub quic: QuicPolicy,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig { timeout: 60, quic: QuicPolicy::default() }
+    }
+}
+
+/// 阻断 QUIC 后浏览器会回退到 TCP，再由 https 入站按 SNI 分流
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuicPolicy {
+    /// 通过 socks5 UDP ASSOCIATE 转发
+    #[default]
+    Relay,
+    /// 直接丢弃数据报，客户端等待超时后回退
+    Drop,
+    /// 丢弃数据报并回复 ICMP 端口不可达，客户端可以立即回退
+    Reject,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SocksConfig {
+    /// socks5 入站的用户名 => 密码，为空时不需要认证
+    pub users: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// 请求头的最大长度，单位字节，超过时按原始目标地址转发
+    pub max_header_size: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig { max_header_size: MAX_HEADER_SIZE }
+    }
+}
+
+/// 客户端使用 Encrypted ClientHello 时，SNI 只是外层的公开名称，按域名分流没有意义。
+/// Chrome 等浏览器的每个连接都会发送 GREASE ECH 扩展，只有外层 SNI 匹配 ech_public_names 的连接才会使用这个策略
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EchPolicy {
+    /// 仍然按外层 SNI 分流
+    #[default]
+    Sni,
+    /// 总是通过代理连接原始目标地址
+    Proxy,
+    /// 按原始目标地址分流，可以配合路由规则的 ips 条件使用
+    Ip,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// 上游 DNS 服务器，名称 => 地址，例如 "local": "223.5.5.5:53"
+    pub servers: HashMap<String, DnsServerConfig>,
+    /// 没有匹配到任何规则时使用的服务器名称，为空时使用系统解析
+    pub default: Option<String>,
+}
+
+/// 上游 DNS 服务器，可以只写地址，也可以指定是否通过代理连接，
+/// 例如 `{ "address": "https://dns.google/dns-query", "proxy": true }`
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DnsServerConfig {
+    Address(String),
+    Detail {
+        address: String,
+        #[serde(default)]
+        proxy: bool,
+        /// DoT/DoH 服务器使用域名时直连的 IP 地址，避免通过系统解析服务器的域名
+        #[serde(default)]
+        bootstrap: Option<IpAddr>,
+    },
+}
+
+/// 代理域名解析出的地址写入的 nftables 集合，集合需要带 timeout 标志
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct NftSetConfig {
+    /// 集合所在的表，格式为 "<family> <name>"，未设置时与安装的 nftables 脚本一致，
+    /// tproxy 模式为 "inet https.tproxy"，否则为 "inet https.nat"
+    pub table: Option<String>,
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+    /// 元素过期时间，单位秒
+    pub timeout: u64,
+    /// 解析代理域名使用的 DNS 服务器名称，必须设置。系统解析服务器对被封锁的域名返回的往往是错误的地址
+    pub dns: Option<String>,
+}
+
+impl Default for NftSetConfig {
+    fn default() -> Self {
+        NftSetConfig {
+            table: None,
+            ipv4: Some("proxy4".to_string()),
+            ipv6: Some("proxy6".to_string()),
+            timeout: 3600,
+            dns: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Proxy,
+    Direct,
+}
+
+/// 重试的条件
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Fallback {
+    /// 第一次连接的超时时间，单位毫秒
+    pub timeout: u64,
+    /// 发送 ClientHello 等第一段数据后，这段时间内连接被重置或者关闭也会重试，单位毫秒，为 0 时只在连接失败时重试
+    pub within: u64,
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback { timeout: 5000, within: 0 }
+    }
+}
+
+/// 一条路由规则，按配置文件中的顺序匹配，第一条命中的生效。
+/// 所有设置了的条件都满足时才算命中，至少需要设置一个条件，匹配所有连接的规则需要显式设置 all。
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Route {
+    /// 匹配的域名，包含所有子域名
+    pub domains: Vec<String>,
+    /// 与 rules.json 格式相同的域名规则文件
+    pub rule_file: Option<String>,
+    /// 匹配 ClientHello 中的 ALPN，例如 "h2"，任意一个相同即命中
+    pub alpn: Vec<String>,
+    /// 匹配客户端的 JA3 指纹
+    pub ja3: Vec<String>,
+    /// 匹配客户端的 JA4 指纹
+    pub ja4: Vec<String>,
+    /// 匹配目标 IP 地址，例如 "10.0.0.0/8"、"2001:db8::/32"，只对没有主机名的连接生效
+    pub ips: Vec<String>,
+    /// 匹配所有连接，不能与其他条件同时使用
+    pub all: bool,
+    /// 命中后通过代理还是直接连接，未设置时使用域名规则文件判断
+    pub action: Option<Action>,
+    /// 直连时用于解析域名的 DNS 服务器名称
+    pub dns: Option<String>,
+    /// 直连时先向服务器发送 PROXY protocol v2 头部，告知真实的客户端地址，服务器需要支持 PROXY protocol
+    pub proxy_protocol: bool,
+    /// 通过代理时使用的上游服务器组，未设置时使用 --proxy 指定的服务器
+    pub group: Option<String>,
+    /// 选择的方式连接失败时改用另一种方式重试，代理失败时直连，直连失败时通过代理
+    pub fallback: Option<Fallback>,
+    #[serde(skip)]
+    rules: Option<Rules>,
+    #[serde(skip)]
+    cidrs: Vec<(IpAddr, u8)>,
+}
+
+impl Config {
+    pub fn from_file(filename: &str) -> Result<Config> {
+        let file = std::fs::File::open(filename)?;
+        let mut config: Config = serde_json::from_reader(file)?;
+        for route in config.routes.iter_mut() {
+            route.compile()?;
+        }
+        Ok(config)
+    }
+}
+
+impl Route {
+    fn compile(&mut self) -> Result<()> {
+        let conditions = !self.domains.is_empty() || self.rule_file.is_some() || !self.alpn.is_empty()
+            || !self.ja3.is_empty() || !self.ja4.is_empty() || !self.ips.is_empty();
+        if conditions == self.all {
+            return Err(anyhow!("a route needs at least one condition, or \"all\": true alone to match every connection"));
+        }
+        for ip in self.ips.iter() {
+            self.cidrs.push(parse_cidr(ip)?);
+        }
+        if self.domains.is_empty() && self.rule_file.is_none() {
+            return Ok(());
+        }
+        let mut rules = match &self.rule_file {
+            Some(f) => Rules::from_file(f)?,
+            None => Rules::new(),
+        };
+        for domain in self.domains.iter() {
+            rules.add(domain);
+        }
+        self.rules = Some(rules);
+        Ok(())
+    }
+    pub fn matches(&self, hostname: Option<&str>, ip: Option<IpAddr>, hello: Option<&ClientHelloInfo>) -> bool {
+        if self.all {
+            return true;
+        }
+        if let Some(rules) = &self.rules {
+            if !hostname.map(|h| rules.matches(h)).unwrap_or(false) {
+                return false;
+            }
+        }
+        if !self.cidrs.is_empty() && !ip.map(|ip| self.cidrs.iter().any(|c| cidr_contains(c, ip))).unwrap_or(false) {
+            return false;
+        }
+        if !self.alpn.is_empty() && !hello.map(|h| h.alpn.iter().any(|a| self.alpn.contains(a))).unwrap_or(false) {
+            return false;
+        }
+        if !self.ja3.is_empty() && !hello.map(|h| self.ja3.contains(&h.ja3())).unwrap_or(false) {
+            return false;
+        }
+        if !self.ja4.is_empty() && !hello.map(|h| self.ja4.contains(&h.ja4())).unwrap_or(false) {
+            return false;
+        }
+        true
+    }
+}
+
+fn parse_cidr(s: &str) -> Result<(IpAddr, u8)> {
+    let (ip, len) = s.split_once('/').unwrap_or((s, ""));
+    let ip: IpAddr = ip.parse().map_err(|e| anyhow!("ip address format error:{} {}",s,e))?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let len: u8 = if len.is_empty() { max } else { len.parse()? };
+    if len > max {
+        return Err(anyhow!("ip address format error:{}",s));
+    }
+    Ok((ip, len))
+}
+
+fn cidr_contains(&(net, len): &(IpAddr, u8), ip: IpAddr) -> bool {
+    match (net, ip.to_canonical()) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Route;
+    use crate::utils::ClientHelloInfo;
+
+    #[test]
+    fn test_route_match_domain() {
+        let mut route = Route { domains: vec!["cn".into(), "example.com".into()], ..Default::default() };
+        route.compile().unwrap();
+        assert!(route.matches(Some("www.baidu.cn"), None, None));
+        assert!(route.matches(Some("a.example.com"), None, None));
+        assert!(!route.matches(Some("example.org"), None, None));
+        assert!(!route.matches(None, None, None));
+    }
+
+    #[test]
+    fn test_route_match_client_hello() {
+        let mut route = Route { alpn: vec!["h2".into()], ..Default::default() };
+        route.compile().unwrap();
+        let hello = ClientHelloInfo { alpn: vec!["h2".into(), "http/1.1".into()], ..Default::default() };
+        assert!(route.matches(None, None, Some(&hello)));
+        assert!(!route.matches(None, None, Some(&ClientHelloInfo::default())));
+        assert!(!route.matches(Some("example.com"), None, None));
+
+        let mut route = Route { domains: vec!["example.com".into()], ja4: vec![hello.ja4()], ..Default::default() };
+        route.compile().unwrap();
+        assert!(route.matches(Some("example.com"), None, Some(&hello)));
+        assert!(!route.matches(Some("example.org"), None, Some(&hello)));
+        assert!(!route.matches(Some("example.com"), None, Some(&ClientHelloInfo::default())));
+        let mut route = Route::default();
+        assert!(route.compile().is_err());
+        let mut route = Route { all: true, ..Default::default() };
+        route.compile().unwrap();
+        assert!(route.matches(None, None, None));
+        let mut route = Route { all: true, alpn: vec!["h2".into()], ..Default::default() };
+        assert!(route.compile().is_err());
+    }
+
+    #[test]
+    fn test_route_match_ip() {
+        let mut route = Route { ips: vec!["10.0.0.0/8".into(), "2001:db8::/32".into(), "1.1.1.1".into()], ..Default::default() };
+        route.compile().unwrap();
+        assert!(route.matches(None, Some("10.1.2.3".parse().unwrap()), None));
+        assert!(route.matches(None, Some("::ffff:10.1.2.3".parse().unwrap()), None));
+        assert!(route.matches(None, Some("2001:db8::1".parse().unwrap()), None));
+        assert!(route.matches(None, Some("1.1.1.1".parse().unwrap()), None));
+        assert!(!route.matches(None, Some("1.1.1.2".parse().unwrap()), None));
+        assert!(!route.matches(Some("example.com"), None, None));
+        let mut route = Route { ips: vec!["10.0.0.0/33".into()], ..Default::default() };
+        assert!(route.compile().is_err());
+    }
+
+    #[test]
+    fn test_route_fallback() {
+        let route: Route = serde_json::from_str(r#"{"action": "direct", "fallback": {"within": 300}}"#).unwrap();
+        let fallback = route.fallback.unwrap();
+        assert_eq!((fallback.timeout, fallback.within), (5000, 300));
+        let route: Route = serde_json::from_str(r#"{"action": "direct"}"#).unwrap();
+        assert!(route.fallback.is_none());
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::prelude::AsRawFd;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use log::{debug, trace};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+use crate::config::{DnsConfig, DnsServerConfig};
+use crate::prelude::*;
+use crate::proxy::Proxy;
+use crate::upstream::Outbound;
+use crate::utils::MAX_HEADER_SIZE;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_TTL: u32 = 10;
+/// 缓存的最大条目数，超过时先清理过期的条目，仍然超过时清空
+const MAX_CACHE_SIZE: usize = 4096;
+/// DNS 报文的最大长度，DoH 响应体不能超过这个长度
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// 上游 DNS 服务器
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// DNS-over-TLS，RFC 7858
+    Tls { host: String, port: u16 },
+    /// DNS-over-HTTPS，RFC 8484，tls 为 false 时使用明文 http，仅用于本地服务器
+    Https { tls: bool, host: String, port: u16, path: String },
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    /// 支持 `223.5.5.5`、`223.5.5.5:53`、`udp://223.5.5.5:53`、`tcp://[2400:3200::1]:53`、
+    /// `tls://1.1.1.1`、`https://dns.google/dns-query` 等格式
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, addr) = s.split_once("://").unwrap_or(("udp", s));
+        if scheme == "udp" || scheme == "tcp" {
+            let addr: SocketAddr = match addr.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 53),
+                Err(_) => addr.parse().map_err(|e| anyhow!("dns server address format error:{} {}",s,e))?,
+            };
+            return Ok(if scheme == "udp" { Upstream::Udp(addr) } else { Upstream::Tcp(addr) });
+        }
+        let u = url::Url::parse(s)?;
+        let host = u.host_str()
+            .ok_or(anyhow!("dns server address format error:{}",s))?
+            .trim_start_matches('[').trim_end_matches(']').to_string();
+        match scheme {
+            "tls" => Ok(Upstream::Tls { host, port: u.port().unwrap_or(853) }),
+            "https" | "http" => Ok(Upstream::Https {
+                tls: scheme == "https",
+                host,
+                port: u.port_or_known_default().unwrap_or(443),
+                path: u.path().to_string(),
+            }),
+            _ => Err(anyhow!("unsupported dns server type: {}",s)),
+        }
+    }
+}
+
+struct Server {
+    upstream: Upstream,
+    /// 是否通过 socks5 代理连接这个服务器
+    proxy: bool,
+    /// 直连时使用的服务器地址，服务器使用域名时必须设置
+    bootstrap: Option<IpAddr>,
+}
+
+type Cache = HashMap<String, (Vec<IpAddr>, Instant)>;
+
+/// 异步域名解析器，直连时根据规则选择上游服务器
+#[derive(Clone)]
+pub struct Resolver {
+    servers: Arc<HashMap<String, Server>>,
+    default: Option<String>,
+    cache: Arc<Mutex<Cache>>,
+    tls: Arc<ClientConfig>,
+    pub fwmark: u16,
+}
+
+impl Resolver {
+    pub fn new(cfg: &DnsConfig) -> Result<Self> {
+        let mut servers = HashMap::new();
+        for (name, server) in cfg.servers.iter() {
+            let (address, proxy, bootstrap) = match server {
+                DnsServerConfig::Address(address) => (address, false, None),
+                DnsServerConfig::Detail { address, proxy, bootstrap } => (address, *proxy, *bootstrap),
+            };
+            let upstream = address.parse::<Upstream>()?;
+            if let Upstream::Tls { host, .. } | Upstream::Https { host, .. } = &upstream {
+                if !proxy && bootstrap.is_none() && host.parse::<IpAddr>().is_err() {
+                    return Err(anyhow!("dns server {} uses a hostname, set bootstrap to its ip address: {}",name,address));
+                }
+            }
+            servers.insert(name.clone(), Server { upstream, proxy, bootstrap });
+        }
+        if let Some(name) = &cfg.default {
+            if !servers.contains_key(name) {
+                return Err(anyhow!("default dns server not found: {}",name));
+            }
+        }
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Resolver {
+            servers: Arc::new(servers),
+            default: cfg.default.clone(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            tls: Arc::new(tls),
+            fwmark: 0,
+        })
+    }
+    pub fn has_server(&self, name: &str) -> bool {
+        self.servers.contains_key(name)
+    }
+
+    /// 使用指定名称的服务器解析域名，server 为空时使用默认服务器，
+    /// 没有配置默认服务器时使用系统解析
+    pub async fn resolve(&self, proxy: &Proxy, hostname: &str, server: Option<&str>) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = hostname.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let Some(name) = server.or(self.default.as_deref()) else {
+            let addrs = tokio::net::lookup_host((hostname, 0)).await?;
+            return Ok(addrs.map(|a| a.ip()).collect());
+        };
+        let key = format!("{}@{}", hostname, name);
+        if let Some((ips, expire)) = self.cache.lock().unwrap().get(&key) {
+            if *expire > Instant::now() {
+                return Ok(ips.clone());
+            }
+        }
+        let (ips, ttl) = self.lookup(Some(proxy), hostname, name).await?;
+        let expire = Instant::now() + Duration::from_secs(ttl.max(MIN_TTL) as u64);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_SIZE {
+            let now = Instant::now();
+            cache.retain(|_, (_, expire)| *expire > now);
+            if cache.len() >= MAX_CACHE_SIZE {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (ips.clone(), expire));
+        Ok(ips)
+    }
+
+    async fn lookup(&self, proxy: Option<&Proxy>, hostname: &str, name: &str) -> Result<(Vec<IpAddr>, u32)> {
+        let server = self.servers.get(name)
+            .ok_or(anyhow!("dns server not found: {}",name))?;
+        let proxy = if server.proxy { proxy } else { None };
+        trace!("resolve {} via {}",hostname,name);
+        let (v4, v6) = tokio::join!(
+            self.query(server, proxy, hostname, TYPE_A),
+            self.query(server, proxy, hostname, TYPE_AAAA)
+        );
+        let mut ips = Vec::new();
+        let mut ttl = u32::MAX;
+        for answer in [v4, v6] {
+            match answer {
+                Ok((list, t)) => {
+                    if !list.is_empty() {
+                        ttl = ttl.min(t);
+                    }
+                    ips.extend(list);
+                }
+                Err(err) => debug!("dns query {} via {} failed: {}",hostname,name,err),
+            }
+        }
+        if ips.is_empty() {
+            return Err(anyhow!("unable to resolve domain name: {}",hostname));
+        }
+        Ok((ips, ttl))
+    }
+
+    async fn query(&self, server: &Server, proxy: Option<&Proxy>, hostname: &str, qtype: u16) -> Result<(Vec<IpAddr>, u32)> {
+        let id = next_id();
+        let query = build_query(id, hostname, qtype)?;
+        let response = timeout(QUERY_TIMEOUT, self.exchange(server, proxy, &query)).await??;
+        parse_response(&response, id, qtype)
+    }
+    async fn exchange(&self, server: &Server, proxy: Option<&Proxy>, query: &[u8]) -> Result<Vec<u8>> {
+        let bootstrap = server.bootstrap;
+        match &server.upstream {
+            Upstream::Udp(addr) if proxy.is_none() => {
+                let response = self.exchange_udp(*addr, query).await?;
+                if response.len() > 2 && response[2] & 0x02 != 0 { // TC，结果被截断，使用 TCP 重试
+                    let stream = connect(*addr, self.fwmark).await?;
+                    exchange_stream(stream, query).await
+                } else {
+                    Ok(response)
+                }
+            }
+            // socks5 代理只能转发 TCP，所以通过代理时 udp 服务器也使用 TCP 查询
+            Upstream::Udp(addr) | Upstream::Tcp(addr) => {
+                let stream = self.dial(proxy, &addr.ip().to_string(), addr.port(), None).await?;
+                exchange_stream(stream, query).await
+            }
+            Upstream::Tls { host, port } => {
+                let stream = self.dial(proxy, host, *port, bootstrap).await?;
+                let stream = self.handshake(stream, host, b"dot").await?;
+                exchange_stream(stream, query).await
+            }
+            Upstream::Https { tls: true, host, port, path } => {
+                let stream = self.dial(proxy, host, *port, bootstrap).await?;
+                let stream = self.handshake(stream, host, b"http/1.1").await?;
+                exchange_http(stream, host, path, query).await
+            }
+            Upstream::Https { tls: false, host, port, path } => {
+                let stream = self.dial(proxy, host, *port, bootstrap).await?;
+                exchange_http(stream, host, path, query).await
+            }
+        }
+    }
+    async fn exchange_udp(&self, addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(..) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(..) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        set_mark(socket.as_raw_fd(), self.fwmark);
+        socket.connect(addr).await?;
+        socket.send(query).await?;
+        let mut buf = vec![0u8; 4096];
+        let n = socket.recv(&mut buf).await?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+    /// 连接上游服务器，proxy 不为空时通过 socks5 代理连接，服务器域名交给代理解析，
+    /// 直连时不解析服务器域名，使用 bootstrap 地址
+    async fn dial(&self, proxy: Option<&Proxy>, host: &str, port: u16, bootstrap: Option<IpAddr>) -> Result<Outbound> {
+        let ip = host.parse::<IpAddr>().ok();
+        if let Some(proxy) = proxy {
+            let target: Target = match ip {
+                Some(ip) => SocketAddr::new(ip, port).into(),
+                None => Target::Hostname(format!("{}:{}", host, port)),
+            };
+            return proxy.dial(&target).await;
+        }
+        let ip = ip.or(bootstrap).ok_or(anyhow!("no bootstrap address for dns server: {}",host))?;
+        Ok(connect(SocketAddr::new(ip, port), self.fwmark).await?.into())
+    }
+    async fn handshake(&self, stream: Outbound, host: &str, alpn: &[u8]) -> Result<tokio_rustls::client::TlsStream<Outbound>> {
+        let mut cfg = (*self.tls).clone();
+        cfg.alpn_protocols = vec![alpn.to_vec()];
+        let name = ServerName::try_from(host.to_string())?;
+        Ok(TlsConnector::from(Arc::new(cfg)).connect(name, stream).await?)
+    }
+}
+
+/// DNS over TCP/TLS，两字节长度前缀，RFC 1035 4.2.2
+async fn exchange_stream<S>(mut stream: S, query: &[u8]) -> Result<Vec<u8>> where S: AsyncRead + AsyncWrite + Unpin {
+    let mut data = Vec::with_capacity(query.len() + 2);
+    data.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    data.extend_from_slice(query);
+    stream.write_all(&data).await?;
+    let n = stream.read_u16().await?;
+    let mut buf = vec![0u8; n as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// DNS over HTTPS，使用 POST 方法发送查询，RFC 8484 4.1。
+/// 按 Content-Length 或者 chunked 读取响应，不依赖服务器关闭连接
+async fn exchange_http<S>(mut stream: S, host: &str, path: &str, query: &[u8]) -> Result<Vec<u8>> where S: AsyncRead + AsyncWrite + Unpin {
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path, host, query.len()).into_bytes();
+    request.extend_from_slice(query);
+    stream.write_all(&request).await?;
+    let mut response = Vec::new();
+    let head_end = loop {
+        if let Some(i) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if response.len() > MAX_HEADER_SIZE {
+            return Err(anyhow!("http response header too long"));
+        }
+        read_more(&mut stream, &mut response).await?;
+    };
+    let mut body = response.split_off(head_end + 4);
+    let head = std::str::from_utf8(&response[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(anyhow!("dns server returned: {}",status));
+    }
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let Some((k, v)) = line.split_once(':') else { continue; };
+        if k.eq_ignore_ascii_case("content-length") {
+            let n = v.trim().parse::<usize>()?;
+            if n > MAX_MESSAGE_SIZE {
+                return Err(anyhow!("dns response too long: {}",n));
+            }
+            length = Some(n);
+        } else if k.eq_ignore_ascii_case("transfer-encoding") && v.trim().eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        }
+    }
+    if chunked {
+        loop {
+            if let Some(data) = decode_chunked(&body)? {
+                return Ok(data);
+            }
+            // 除了数据之外还有每一块的长度行和 trailer
+            if body.len() > MAX_MESSAGE_SIZE + MAX_HEADER_SIZE {
+                return Err(anyhow!("dns response too long"));
+            }
+            read_more(&mut stream, &mut body).await?;
+        }
+    }
+    match length {
+        Some(n) => {
+            while body.len() < n {
+                read_more(&mut stream, &mut body).await?;
+            }
+            body.truncate(n);
+            Ok(body)
+        }
+        None => {
+            // 没有长度时读到连接关闭，TLS 连接没有发送 close_notify 也接受
+            let limit = (MAX_MESSAGE_SIZE + 1).saturating_sub(body.len()) as u64;
+            match (&mut stream).take(limit).read_to_end(&mut body).await {
+                Err(err) if err.kind() != std::io::ErrorKind::UnexpectedEof => Err(err.into()),
+                _ if body.len() > MAX_MESSAGE_SIZE => Err(anyhow!("dns response too long")),
+                _ => Ok(body),
+            }
+        }
+    }
+}
+
+/// 继续读取数据，连接已经关闭时返回错误
+async fn read_more<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<()> where S: AsyncRead + Unpin {
+    let mut chunk = [0u8; 4096];
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+        return Err(anyhow!("incomplete http response"));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+/// 解码 chunked 编码的响应体，数据不完整时返回 None
+fn decode_chunked(mut body: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    loop {
+        let Some(line_end) = body.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)?;
+        if size == 0 {
+            return Ok(Some(data));
+        }
+        if size > MAX_MESSAGE_SIZE - data.len() {
+            return Err(anyhow!("dns response too long"));
+        }
+        let Some(chunk) = body.get(line_end + 2..line_end + 2 + size) else {
+            return Ok(None);
+        };
+        data.extend_from_slice(chunk);
+        let Some(rest) = body.get(line_end + 4 + size..) else {
+            return Ok(None);
+        };
+        body = rest;
+    }
+}
+
+fn next_id() -> u16 {
+    static COUNTER: AtomicU16 = AtomicU16::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default();
+    (nanos as u16) ^ COUNTER.fetch_add(0x9e37, Ordering::Relaxed)
+}
+
+pub fn build_query(id: u16, hostname: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(18 + hostname.len());
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]); // RD，一个问题
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid hostname: {}",hostname));
+        }
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+    data.extend_from_slice(&qtype.to_be_bytes());
+    data.extend_from_slice(&[0x00, 0x01]); // IN
+    Ok(data)
+}
+
+/// 解析 DNS 响应，返回查询类型对应的地址列表和最小 TTL
+pub fn parse_response(buf: &[u8], id: u16, qtype: u16) -> Result<(Vec<IpAddr>, u32)> {
+    if buf.len() < 12 {
+        return Err(anyhow!("dns response is too short"));
+    }
+    if u16::from_be_bytes([buf[0], buf[1]]) != id {
+        return Err(anyhow!("dns response id mismatch"));
+    }
+    if buf[2] & 0x80 == 0 {
+        return Err(anyhow!("this is not a dns response"));
+    }
+    let rcode = buf[3] & 0x0f;
+    if rcode != 0 {
+        return Err(anyhow!("dns server returned error code:{}",rcode));
+    }
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+    let ancount = u16::from_be_bytes([buf[6], buf[7]]);
+    let mut i = 12;
+    for _ in 0..qdcount {
+        i = skip_name(buf, i)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..ancount {
+        i = skip_name(buf, i)?;
+        let header = buf.get(i..i + 10).ok_or(anyhow!("corrupted dns response"))?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        i += 10;
+        let rdata = buf.get(i..i + rdlength).ok_or(anyhow!("corrupted dns response"))?;
+        i += rdlength;
+        if rtype != qtype {
+            continue; // CNAME 等记录
+        }
+        match rdata.len() {
+            4 => ips.push(IpAddr::from(<[u8; 4]>::try_from(rdata)?)),
+            16 => ips.push(IpAddr::from(<[u8; 16]>::try_from(rdata)?)),
+            _ => return Err(anyhow!("corrupted dns response")),
+        }
+        ttl = ttl.min(rttl);
+    }
+    Ok((ips, ttl))
+}
+
+fn skip_name(buf: &[u8], mut i: usize) -> Result<usize> {
+    loop {
+        let len = *buf.get(i).ok_or(anyhow!("corrupted dns response"))? as usize;
+        if len & 0xc0 == 0xc0 { // 压缩指针
+            return Ok(i + 2);
+        }
+        if len == 0 {
+            return Ok(i + 1);
+        }
+        i += 1 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::config::{DnsConfig, DnsServerConfig};
+    use crate::dns::{build_query, exchange_http, exchange_stream, parse_response, Resolver, TYPE_A, Upstream};
+
+    #[test]
+    fn test_upstream_from_str() {
+        assert_eq!("223.5.5.5".parse::<Upstream>().unwrap(), Upstream::Udp("223.5.5.5:53".parse().unwrap()));
+        assert_eq!("tcp://[::1]:5353".parse::<Upstream>().unwrap(), Upstream::Tcp("[::1]:5353".parse().unwrap()));
+        assert_eq!("tls://1.1.1.1".parse::<Upstream>().unwrap(), Upstream::Tls { host: "1.1.1.1".into(), port: 853 });
+        assert_eq!("https://dns.google/dns-query".parse::<Upstream>().unwrap(),
+                   Upstream::Https { tls: true, host: "dns.google".into(), port: 443, path: "/dns-query".into() });
+        assert!("quic://1.1.1.1".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = build_query(0x1234, "example.com", TYPE_A).unwrap();
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2; // ANCOUNT
+        // CNAME example.com => c.example.com
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 0x01, b'c', 0xc0, 0x0c]);
+        // A c.example.com => 93.184.216.34
+        response.extend_from_slice(&[0xc0, 0x29, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 93, 184, 216, 34]);
+        let (ips, ttl) = parse_response(&response, 0x1234, TYPE_A).unwrap();
+        assert_eq!(ips, vec!["93.184.216.34".parse::<IpAddr>().unwrap()]);
+        assert_eq!(ttl, 300);
+        assert!(parse_response(&response, 0x4321, TYPE_A).is_err());
+        assert!(parse_response(&response[..response.len() - 2], 0x1234, TYPE_A).is_err());
+    }
+
+    /// 本地 DoH 服务器，对所有 A 查询返回 127.0.0.2，其它查询返回空结果
+    async fn doh_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    let head_end = loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break i + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+                    assert!(head.starts_with("POST /dns-query HTTP/1.1\r\n"));
+                    assert!(head.contains("Content-Type: application/dns-message\r\n"));
+                    let length: usize = head.split("\r\n")
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .unwrap().parse().unwrap();
+                    while request.len() < head_end + length {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let mut response = request[head_end..head_end + length].to_vec();
+                    response[2] |= 0x80;
+                    if response[response.len() - 3] == TYPE_A as u8 {
+                        response[7] = 1;
+                        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 127, 0, 0, 2]);
+                    }
+                    let mut data = format!("HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n", response.len()).into_bytes();
+                    data.extend_from_slice(&response);
+                    stream.write_all(&data).await.unwrap();
+                });
+            }
+        });
+        format!("http://{}/dns-query", addr)
+    }
+
+    #[tokio::test]
+    async fn test_doh_lookup() {
+        let mut cfg = DnsConfig::default();
+        cfg.servers.insert("doh".into(), DnsServerConfig::Address(doh_server().await));
+        let resolver = Resolver::new(&cfg).unwrap();
+        let (ips, ttl) = resolver.lookup(None, "example.com", "doh").await.unwrap();
+        assert_eq!(ips, vec!["127.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(ttl, 60);
+        assert!(resolver.lookup(None, "example.com", "missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_stream() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move { exchange_stream(client, b"query").await });
+        assert_eq!(server.read_u16().await.unwrap(), 5);
+        let mut query = [0u8; 5];
+        server.read_exact(&mut query).await.unwrap();
+        assert_eq!(&query, b"query");
+        // 长度前缀和数据分开发送
+        server.write_all(&[0x00]).await.unwrap();
+        server.write_all(&[0x08, b'r', b'e']).await.unwrap();
+        server.write_all(b"sponse").await.unwrap();
+        assert_eq!(task.await.unwrap().unwrap(), b"response");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_http() {
+        // 服务器保持连接不关闭，按 Content-Length 读取
+        let (client, mut server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { exchange_http(client, "dns.example", "/dns-query", b"query").await });
+        let mut buf = [0u8; 1024];
+        let _ = server.read(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nresp").await.unwrap();
+        server.write_all(b"onse").await.unwrap();
+        assert_eq!(task.await.unwrap().unwrap(), b"response");
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { exchange_http(client, "dns.example", "/dns-query", b"query").await });
+        let _ = server.read(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nresp\r\n").await.unwrap();
+        server.write_all(b"4\r\nonse\r\n0\r\n\r\n").await.unwrap();
+        assert_eq!(task.await.unwrap().unwrap(), b"response");
+        drop(server);
+
+        // 响应体不能超过 DNS 报文的最大长度
+        let (client, mut server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { exchange_http(client, "dns.example", "/dns-query", b"query").await });
+        let _ = server.read(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 65536\r\n\r\n").await.unwrap();
+        assert!(task.await.unwrap().is_err());
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { exchange_http(client, "dns.example", "/dns-query", b"query").await });
+        let _ = server.read(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffff\r\n").await.unwrap();
+        assert!(task.await.unwrap().is_err());
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { exchange_http(client, "dns.example", "/dns-query", b"query").await });
+        let _ = server.read(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        let data = vec![0u8; 4096];
+        for _ in 0..17 {
+            if server.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        assert!(task.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let mut cfg = DnsConfig::default();
+        cfg.servers.insert("dot".into(), DnsServerConfig::Address("tls://dns.google".into()));
+        assert!(Resolver::new(&cfg).is_err());
+        cfg.servers.insert("dot".into(), DnsServerConfig::Detail { address: "tls://dns.google".into(), proxy: false, bootstrap: Some("8.8.8.8".parse().unwrap()) });
+        assert!(Resolver::new(&cfg).is_ok());
+        cfg.servers.insert("dot".into(), DnsServerConfig::Detail { address: "tls://dns.google".into(), proxy: true, bootstrap: None });
+        assert!(Resolver::new(&cfg).is_ok());
+    }
+}
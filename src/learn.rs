@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::config::LearnConfig;
+use crate::prelude::*;
+use crate::psl::PublicSuffixList;
+
+/// 自动学习直连失败的域名
+#[derive(Clone)]
+pub struct Learner {
+    /// 直连的超时时间
+    pub timeout: Duration,
+    /// 发送第一段数据后等待连接被重置的时间
+    pub within: Duration,
+    /// 用于计算学习到的主机名对应的可注册域名
+    pub psl: Arc<PublicSuffixList>,
+    /// 用于对比直连地址的可信 DNS 服务器名称
+    pub dns: Option<String>,
+    log: Option<Arc<Mutex<File>>>,
+}
+
+impl Learner {
+    pub fn new(cfg: &LearnConfig, psl: Arc<PublicSuffixList>) -> Result<Self> {
+        let log = match &cfg.log {
+            Some(path) => Some(Arc::new(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?))),
+            None => None,
+        };
+        Ok(Learner { timeout: Duration::from_millis(cfg.timeout), within: Duration::from_millis(cfg.within), psl, dns: cfg.dns.clone(), log })
+    }
+    /// 记录学习到的域名，每行格式为 "时间戳 域名 主机名 原因"
+    pub fn record(&self, domain: &str, hostname: &str, reason: &str) {
+        info!("[learn] add proxy domain: {} ({}: {})",domain,hostname,reason);
+        let Some(log) = &self.log else { return; };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let line = format!("{} {} {} {}\n", time, domain, hostname, reason.replace('\n', " "));
+        if let Err(err) = log.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("[learn] unable to write log: {}",err);
+        }
+    }
+}
+
+/// 主机名是否被解析到回环地址或者未指定地址，这是被污染的 DNS 常见的返回值，正常的公网域名不会解析到这些地址。
+/// 没有配置可信的 DNS 服务器时只能依靠这项检查
+pub fn is_poisoned(hostname: &str, ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    hostname != "localhost" && !hostname.ends_with(".localhost") && (ip.is_loopback() || ip.is_unspecified())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::LearnConfig;
+    use crate::learn::{is_poisoned, Learner};
+
+    #[test]
+    fn test_learner() {
+        assert!(is_poisoned("www.google.com", "127.0.0.1".parse().unwrap()));
+        assert!(is_poisoned("www.google.com", "::ffff:0.0.0.0".parse().unwrap()));
+        assert!(!is_poisoned("localhost", "127.0.0.1".parse().unwrap()));
+        assert!(!is_poisoned("www.google.com", "142.250.0.1".parse().unwrap()));
+
+        let path = std::env::temp_dir().join(format!("harmony-learn-{}.log", std::process::id()));
+        let cfg = LearnConfig { log: Some(path.to_string_lossy().into()), ..Default::default() };
+        let learner = Learner::new(&cfg, Default::default()).unwrap();
+        learner.record("google.com", "www.google.com", "connection reset");
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(data.ends_with(" google.com www.google.com connection reset\n"));
+    }
+}
@@ -1,25 +1,50 @@
 extern crate core;
 
 use std::{process, str};
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use clap::{Arg, ArgAction, Command};
-use clap::builder::Str;
 use log::{debug, error, info, warn};
 use sd_notify::NotifyState;
-use tokio::join;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
+use crate::config::{Config, Strategy};
+use crate::dns::Resolver;
+use crate::learn::Learner;
+use crate::psl::PublicSuffixList;
+use crate::nftset::NftSet;
 use crate::proxy::*;
 use crate::rule::*;
-use crate::utils::{combine, get_http_domain, get_https_domain, get_target_address};
+use crate::prelude::{local_addresses, Result, set_transparent};
+use crate::proxy_protocol::{HEADER_TIMEOUT, read_header};
+use crate::udp::listen_udp;
+use crate::upstream::{Hop, spawn_check, Upstreams};
+use crate::utils::{combine, get_http_domain, get_target_address};
 
 mod utils;
 mod prelude;
 mod rule;
 mod proxy;
 mod rules;
+mod config;
+mod dns;
+mod nftset;
+mod http;
+mod socks;
+mod quic;
+mod udp;
+mod sniff;
+mod proxy_protocol;
+mod upstream;
+mod learn;
+mod psl;
 
 const INSTALL_FILES: &[(&[u8], &str, u32); 4] = &[
     (include_bytes!("../harmony-rs.service"), "/etc/systemd/system/harmony-rs.service", 0o644),
@@ -28,6 +53,12 @@ const INSTALL_FILES: &[(&[u8], &str, u32); 4] = &[
     (include_bytes!("../pre.sh"), "/etc/harmony-rs/pre.sh", 0o755)
 ];
 
+/// TPROXY 模式下替换 INSTALL_FILES 中的 nftables 规则
+const TPROXY_FILES: &[(&[u8], &str); 2] = &[
+    (include_bytes!("../tproxy-post.sh"), "/etc/harmony-rs/post.sh"),
+    (include_bytes!("../tproxy-pre.sh"), "/etc/harmony-rs/pre.sh"),
+];
+
 #[tokio::main]
 async fn main() {
     let args = Command::new("https-proxy")
@@ -38,10 +69,17 @@ async fn main() {
             .short('x')
             .default_value("127.0.0.1:1080")
             .action(ArgAction::Set)
+            .help("socks5 proxy servers separated by commas, the first available one is used")
             .required(false))
         .arg(Arg::new("rule")
             .long("rule-file")
             .action(ArgAction::Set).required(false))
+        .arg(Arg::new("config")
+            .long("config")
+            .short('c')
+            .action(ArgAction::Set)
+            .help("configuration file in json format")
+            .required(false))
         .arg(Arg::new("http-port")
             .long("http-port")
             .default_value("8080")
@@ -52,6 +90,36 @@ async fn main() {
             .default_value("8433")
             .action(ArgAction::Set)
             .required(false))
+        .arg(Arg::new("forward-port")
+            .long("forward-port")
+            .action(ArgAction::Set)
+            .help("listen as an explicit http proxy (CONNECT and absolute-form requests), [address:]port, only on 127.0.0.1 when no address is given")
+            .required(false))
+        .arg(Arg::new("socks-port")
+            .long("socks-port")
+            .action(ArgAction::Set)
+            .help("listen as a socks5 proxy")
+            .required(false))
+        .arg(Arg::new("auto-port")
+            .long("auto-port")
+            .action(ArgAction::Set)
+            .help("listen for any protocol, detected from the first bytes (tls, http, socks5)")
+            .required(false))
+        .arg(Arg::new("tproxy")
+            .long("tproxy")
+            .action(ArgAction::SetTrue)
+            .help("listen on transparent sockets for nftables tproxy rules instead of redirect")
+            .required(false))
+        .arg(Arg::new("proxy-protocol")
+            .long("proxy-protocol")
+            .action(ArgAction::SetTrue)
+            .help("expect a haproxy PROXY protocol v1/v2 header on connections to the http and https ports")
+            .required(false))
+        .arg(Arg::new("udp-port")
+            .long("udp-port")
+            .action(ArgAction::Set)
+            .help("relay udp (quic) traffic in tproxy mode")
+            .required(false))
         .arg(Arg::new("fwmark")
             .long("fwmark")
             .action(ArgAction::Set)
@@ -74,6 +142,11 @@ async fn main() {
                 .action(ArgAction::SetTrue)
                 .help("overwrite existing cfg file if set")
             )
+            .arg(Arg::new("tproxy")
+                .long("tproxy")
+                .action(ArgAction::SetTrue)
+                .help("install nftables tproxy rules instead of redirect rules")
+            )
         )
         .get_matches();
     if args.get_flag("debug") {
@@ -94,7 +167,7 @@ async fn main() {
         env_logger::init();
     }
     match args.subcommand() {
-        Some(("install", installArgs)) => {
+        Some(("install", install_args)) => {
             let exe = std::env::current_exe().expect("failed to get current executable path");
             use std::path::Path;
             use std::fs;
@@ -108,17 +181,25 @@ async fn main() {
                     process::exit(255);
                 }
             }
-            let overwrite = installArgs.get_flag("overwrite");
+            let overwrite = install_args.get_flag("overwrite");
+            let tproxy = install_args.get_flag("tproxy");
             for &(data, file, m) in INSTALL_FILES {
+                let data = match TPROXY_FILES.iter().find(|(_, f)| tproxy && *f == file) {
+                    Some(&(data, _)) => data,
+                    None => data,
+                };
                 if !overwrite && Path::new(file).exists() {
                     info!("ignore exist file: {}",file);
                     continue;
                 }
                 info!("cp {}",file);
-                let mut service = String::new();
+                let mut service: String;
                 let data = if file == "/etc/systemd/system/harmony-rs.service" {
                     service = std::str::from_utf8(data).unwrap()
                         .replace("/usr/local/bin/harmony-rs", exe.to_str().unwrap());
+                    if tproxy {
+                        service = service.replace("--enable-control-pipe", "--enable-control-pipe \\\n            --tproxy \\\n            --udp-port 8443");
+                    }
                     service.as_bytes()
                 } else {
                     data
@@ -140,84 +221,271 @@ async fn main() {
     }
 
     let proxy_address = args.get_one::<String>("proxy").unwrap();
-    let proxy_address: SocketAddr = match proxy_address.parse() {
-        Ok(addr) => addr,
+    let proxy_address: Vec<SocketAddr> = match proxy_address.split(',').map(|a| a.trim().parse()).collect() {
+        Ok(addrs) => addrs,
         Err(err) => {
             error!("socks5 proxy address format error:{} {}",proxy_address,err);
             return;
         }
     };
-    info!("proxy server:{}", proxy_address);
+    info!("proxy server:{:?}", proxy_address);
     let rule_file = args.get_one::<String>("rule").map(|s| s.to_string());
     let ctrl = std::env::var("CTRL_FILE")
         .unwrap_or("/run/harmony-rs".to_string());
     let ctrl: Option<String> = if args.get_flag("ctrl") { Some(ctrl) } else { None };
-    let rule = match RuleEngine::from_file(rule_file, ctrl) {
+    // 上游服务器的状态写入控制管道旁边的文件
+    let status = ctrl.as_ref().map(|c| format!("{}.status", c));
+    let config = match args.get_one::<String>("config") {
+        Some(f) => match Config::from_file(f) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("unable to load config file: {}",err);
+                return;
+            }
+        },
+        None => Config::default(),
+    };
+    let mut dns = match Resolver::new(&config.dns) {
+        Ok(r) => r,
+        Err(err) => {
+            error!("dns config error: {}",err);
+            return;
+        }
+    };
+    let nftset_dns = config.nftset.as_ref().and_then(|c| c.dns.as_ref());
+    let learn_dns = config.learn.dns.as_ref().filter(|_| config.learn.enabled);
+    if let Some(name) = config.routes.iter().filter_map(|r| r.dns.as_ref()).chain(nftset_dns).chain(learn_dns).find(|n| !dns.has_server(n)) {
+        error!("dns server not found: {}",name);
+        return;
+    }
+    if let Some(name) = config.routes.iter().filter_map(|r| r.group.as_ref()).find(|n| !config.groups.contains_key(*n)) {
+        error!("upstream group not found: {}",name);
+        return;
+    }
+    let psl = match config.psl.file.as_deref().map(PublicSuffixList::from_file).transpose() {
+        Ok(psl) => Arc::new(psl.unwrap_or_default()),
+        Err(err) => {
+            error!("unable to load public suffix list: {}",err);
+            return;
+        }
+    };
+    let rule = match RuleEngine::from_file(rule_file, ctrl, config.routes, psl.clone(), config.psl.normalize) {
         Ok(r) => { r }
         Err(err) => {
             error!("unable to load rule file: {}",err);
             return;
         }
     };
-    let mut proxy = Proxy::from_addr(proxy_address, rule);
+    let mut fwmark = 0;
     if let Some(mark) = args.get_one::<String>("fwmark") {
-        proxy.fwmark = mark.parse::<u16>().expect("fwmark must be a number");
-        debug!("use fwmark: {}",proxy.fwmark);
-    }
-
-    let https_job = { // https 代理
-        let port: &String = args.get_one("https-port").expect("https listening port is invalid");
-        let bind = TcpListener::bind(format!("[::]:{}", port)).await.unwrap();
-        let proxy_copy = proxy.clone();
-        tokio::spawn(async move {
-            loop {
-                let (client, addr) = match bind.accept().await {
-                    Ok(v) => {
-                        v
-                    }
-                    Err(err) => {
-                        warn!("service shutdown: {}",err);
-                        return;
-                    }
-                };
-                debug!("new connection: {}", addr);
-                let p = proxy_copy.clone();
-                tokio::spawn(async move {
-                    p.handler_https(client).await;
-                });
+        fwmark = mark.parse::<u16>().expect("fwmark must be a number");
+        debug!("use fwmark: {}",fwmark);
+    }
+    dns.fwmark = fwmark;
+    let mut upstreams = Upstreams::new("default", proxy_address, Strategy::Failover);
+    upstreams.timeout = Duration::from_secs(config.upstream.timeout);
+    match Hop::parse_chain(&config.upstream.via) {
+        Ok(via) => upstreams.via = Arc::new(via),
+        Err(err) => {
+            error!("upstream via format error: {}",err);
+            return;
+        }
+    }
+    let mut groups = HashMap::new();
+    for (name, group) in config.groups.iter() {
+        let servers: Vec<SocketAddr> = match group.servers.iter().map(|a| a.parse()).collect() {
+            Ok(servers) => servers,
+            Err(err) => {
+                error!("upstream group {} address format error: {}",name,err);
+                return;
             }
-        })
+        };
+        if servers.is_empty() {
+            error!("upstream group {} has no server",name);
+            return;
+        }
+        let mut g = Upstreams::new(name, servers, group.strategy);
+        g.timeout = upstreams.timeout;
+        match Hop::parse_chain(&group.via) {
+            Ok(via) => g.via = Arc::new(via),
+            Err(err) => {
+                error!("upstream group {} via format error: {}",name,err);
+                return;
+            }
+        }
+        groups.insert(name.clone(), g);
+    }
+    let probe = match config.upstream.probe.as_deref().map(|p| http::parse_authority(p, 80)).transpose() {
+        Ok(probe) => probe,
+        Err(err) => {
+            error!("upstream probe format error: {}",err);
+            return;
+        }
     };
+    let mut proxy = Proxy::new(upstreams, rule, dns);
+    proxy.groups = Arc::new(groups);
+    proxy.fwmark = fwmark;
+    proxy.ech = config.ech;
+    if let Some(names) = config.ech_public_names {
+        proxy.ech_public_names = Arc::new(names);
+    }
+    proxy.max_header_size = config.http.max_header_size;
+    proxy.socks_users = Arc::new(config.socks.users);
+    if config.learn.enabled {
+        match Learner::new(&config.learn, psl) {
+            Ok(learner) => proxy.learner = Some(learner),
+            Err(err) => {
+                error!("unable to open learn log: {}",err);
+                return;
+            }
+        }
+    }
+    if let Some(cfg) = &config.nftset {
+        match NftSet::new(cfg, args.get_flag("tproxy")) {
+            Ok(set) => proxy.nftset = Some(set),
+            Err(err) => {
+                error!("nftset config error: {}",err);
+                return;
+            }
+        }
+    }
 
-    let http_job = {
-        let port: &String = args.get_one("http-port").expect("http listening port is invalid");
-        let bind = TcpListener::bind(format!("[::]:{}", port)).await.unwrap();
-        let proxy_copy = proxy.clone();
-        tokio::spawn(async move {
-            loop {
-                let (client, addr) = match bind.accept().await {
-                    Ok(v) => {
-                        v
-                    }
-                    Err(err) => {
-                        warn!("service shutdown: {}",err);
-                        return;
-                    }
-                };
-                debug!("new connection: {}", addr);
-                let p = proxy_copy.clone();
-                tokio::spawn(async move {
-                    p.handler_http(client).await;
-                });
-            };
-        })
-    };
+    let https_port: &String = args.get_one("https-port").expect("https listening port is invalid");
+    let http_port: &String = args.get_one("http-port").expect("http listening port is invalid");
+    let tproxy = args.get_flag("tproxy");
+    let proxy_protocol = args.get_flag("proxy-protocol");
+    let any = IpAddr::from(Ipv6Addr::UNSPECIFIED);
+    let mut listeners = vec![(https_port, Inbound::Https, any), (http_port, Inbound::Http, any)]; // https 代理
+    // HTTP 代理没有认证，默认只允许本机使用
+    let loopback = IpAddr::from(Ipv4Addr::LOCALHOST);
+    for (name, inbound, ip) in [("forward-port", Inbound::Forward, loopback), ("socks-port", Inbound::Socks, any), ("auto-port", Inbound::Auto, any)] {
+        if let Some(port) = args.get_one::<String>(name) {
+            listeners.push((port, inbound, ip));
+        }
+    }
+    if config.upstream.interval > 0 {
+        let mut all = vec![proxy.upstreams.clone()];
+        all.extend(proxy.groups.values().cloned());
+        spawn_check(all, Duration::from_secs(config.upstream.interval), probe, fwmark, status);
+    }
+    let mut jobs = Vec::new();
+    for (port, inbound, ip) in listeners {
+        // 负载均衡器只会转发重定向的 http/https 流量，客户端直接连接的代理端口不要求 PROXY protocol 头部
+        let proxy_protocol = proxy_protocol && matches!(inbound, Inbound::Http | Inbound::Https);
+        match listen(port, ip, proxy.clone(), inbound, tproxy, proxy_protocol).await {
+            Ok(job) => jobs.push(job),
+            Err(err) => {
+                error!("unable to listen on port {}: {}",port,err);
+                return;
+            }
+        }
+    }
     if let Err(e) = sd_notify::notify(true, &[NotifyState::Ready]) {
         info!("sd_notify err: {}",e);
     }
-    let (_, _) = join!(http_job,https_job);
+    if let Some(port) = args.get_one::<String>("udp-port") {
+        if !tproxy {
+            error!("--udp-port requires --tproxy");
+            return;
+        }
+        let idle = Duration::from_secs(config.udp.timeout);
+        match port.parse().map_err(anyhow::Error::from).map(|port| listen_udp(port, proxy.clone(), idle, config.udp.quic)) {
+            Ok(job) => match job.await {
+                Ok(job) => jobs.push(job),
+                Err(err) => {
+                    error!("unable to listen on udp port {}: {}",port,err);
+                    return;
+                }
+            },
+            Err(err) => {
+                error!("udp port format error: {} {}",port,err);
+                return;
+            }
+        }
+    }
+    for job in jobs {
+        let _ = job.await;
+    }
+}
+
+/// 监听端口，每个连接交给对应类型的处理函数。port 可以是 `端口` 或者 `地址:端口`，只有端口时监听 ip。
+/// tproxy 为 true 时监听透明代理 socket，连接的本地地址就是原始目标地址，否则通过 SO_ORIGINAL_DST 获取。
+/// proxy_protocol 为 true 时先读取 PROXY protocol 头部，使用其中的客户端地址和原始目标地址
+async fn listen(port: &str, ip: IpAddr, proxy: Proxy, inbound: Inbound, tproxy: bool, proxy_protocol: bool) -> Result<JoinHandle<()>> {
+    let listen_addr = match port.parse::<u16>() {
+        Ok(port) => SocketAddr::new(ip, port),
+        Err(_) => port.parse::<SocketAddr>()?,
+    };
+    let bind = if tproxy {
+        let socket = if listen_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        set_transparent(socket.as_raw_fd(), listen_addr.is_ipv6())?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(listen_addr)?;
+        socket.listen(1024)?
+    } else {
+        TcpListener::bind(listen_addr).await?
+    };
+    // 启动时获取一次本机地址，用于区分直接连接监听端口的客户端
+    let locals = if tproxy { local_addresses()? } else { HashSet::new() };
+    Ok(tokio::spawn(async move {
+        loop {
+            let (client, addr) = match bind.accept().await {
+                Ok(v) => {
+                    v
+                }
+                Err(err) => {
+                    warn!("service shutdown: {}",err);
+                    return;
+                }
+            };
+            let dst = if tproxy {
+                // 直接连接监听端口的客户端没有原始目标地址
+                client.local_addr().ok()
+                    .map(|a| SocketAddr::new(a.ip().to_canonical(), a.port()))
+                    .filter(|a| !is_listen_addr(*a, listen_addr, &locals))
+            } else {
+                get_target_address(&client)
+            };
+            debug!("new connection: {} ({:?})", addr, inbound);
+            let p = proxy.clone();
+            tokio::spawn(async move {
+                let (mut client, mut addr, mut dst) = (client, addr, dst);
+                if proxy_protocol {
+                    let header = match timeout(HEADER_TIMEOUT, read_header(&mut client)).await {
+                        Ok(Ok(header)) => header,
+                        Ok(Err(err)) => {
+                            warn!("[proxy-protocol] {} invalid header: {}",addr,err);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("[proxy-protocol] {} timeout reading header",addr);
+                            return;
+                        }
+                    };
+                    debug!("[proxy-protocol] {} ==> {:?} ({:?})",addr,header.src,header.dst);
+                    // LOCAL 命令没有地址，保留连接本身的地址
+                    if let (Some(src), Some(d)) = (header.src, header.dst) {
+                        addr = src;
+                        dst = Some(SocketAddr::new(d.ip().to_canonical(), d.port()));
+                    }
+                }
+                p.handle(inbound, client, addr, dst).await;
+            });
+        }
+    }))
 }
 
+/// 连接的本地地址是否就是监听的地址。监听在任意地址时，端口相同并且 ip 是本机网卡上的地址才算，
+/// 被 TPROXY 转发过来的连接的本地地址是原始目标地址，不是本机地址
+fn is_listen_addr(local: SocketAddr, bind: SocketAddr, locals: &HashSet<IpAddr>) -> bool {
+    if local.port() != bind.port() {
+        return false;
+    }
+    if !bind.ip().is_unspecified() {
+        return local.ip() == bind.ip().to_canonical();
+    }
+    locals.contains(&local.ip())
+}
 
 fn chmod(file: &str, m: u32) -> anyhow::Result<()> {
     use std::ffi::CString;
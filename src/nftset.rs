@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use tokio::process::Command;
+
+use crate::config::NftSetConfig;
+use crate::prelude::*;
+
+/// 将代理域名解析出的地址写入 nftables 集合，类似 dnsmasq 的 nftset 功能
+#[derive(Clone)]
+pub struct NftSet {
+    family: String,
+    table: String,
+    ipv4: Option<String>,
+    ipv6: Option<String>,
+    timeout: u64,
+    /// 解析代理域名使用的 DNS 服务器名称
+    pub dns: String,
+    /// 最近写入的地址，避免每个连接都执行一次 nft 命令
+    added: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+impl NftSet {
+    /// 未设置 table 时，tproxy 模式使用 tproxy-pre.sh 创建的 "inet https.tproxy"，否则使用 pre.sh 创建的 "inet https.nat"
+    pub fn new(cfg: &NftSetConfig, tproxy: bool) -> Result<Self> {
+        let table = cfg.table.as_deref()
+            .unwrap_or(if tproxy { "inet https.tproxy" } else { "inet https.nat" });
+        let (family, table) = table.split_once(' ')
+            .ok_or(anyhow!("nftset table format error, expect \"<family> <name>\": {}",table))?;
+        if cfg.timeout == 0 {
+            return Err(anyhow!("nftset timeout must be greater than 0"));
+        }
+        let dns = cfg.dns.clone()
+            .ok_or(anyhow!("nftset requires a dns server to resolve proxied domains"))?;
+        Ok(NftSet {
+            family: family.to_string(),
+            table: table.trim().to_string(),
+            ipv4: cfg.ipv4.clone(),
+            ipv6: cfg.ipv6.clone(),
+            timeout: cfg.timeout,
+            dns,
+            added: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub async fn add(&self, ips: &[IpAddr]) {
+        let ips = self.pending(ips, Instant::now());
+        for (set, v4) in [(&self.ipv4, true), (&self.ipv6, false)] {
+            let Some(set) = set else { continue; };
+            let list: Vec<IpAddr> = ips.iter().filter(|ip| ip.is_ipv4() == v4).cloned().collect();
+            if list.is_empty() {
+                continue;
+            }
+            let args = self.element_args(set, &list);
+            debug!("nft {}",args.join(" "));
+            match Command::new("nft").args(&args).status().await {
+                Ok(status) if status.success() => self.mark(&list, Instant::now()),
+                Ok(status) => warn!("nft add element {} failed: {}",set,status),
+                Err(err) => warn!("unable to execute nft: {}",err),
+            }
+        }
+    }
+
+    /// 需要写入的地址：没有写入过，或者距离上次写入已经超过过期时间的一半
+    fn pending(&self, ips: &[IpAddr], now: Instant) -> Vec<IpAddr> {
+        let refresh = Duration::from_secs(self.timeout / 2);
+        let mut added = self.added.lock().unwrap();
+        added.retain(|_, t| now.duration_since(*t) < refresh);
+        ips.iter().filter(|ip| !added.contains_key(ip)).cloned().collect()
+    }
+
+    /// 记录成功写入的地址，写入失败的地址下次仍然会重试
+    fn mark(&self, ips: &[IpAddr], now: Instant) {
+        let mut added = self.added.lock().unwrap();
+        for ip in ips {
+            added.insert(*ip, now);
+        }
+    }
+
+    fn element_args(&self, set: &str, ips: &[IpAddr]) -> Vec<String> {
+        let elements: Vec<String> = ips.iter()
+            .map(|ip| format!("{} timeout {}s", ip, self.timeout))
+            .collect();
+        vec![
+            "add".to_string(),
+            "element".to_string(),
+            self.family.clone(),
+            self.table.clone(),
+            set.to_string(),
+            format!("{{ {} }}", elements.join(", ")),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::config::NftSetConfig;
+    use crate::nftset::NftSet;
+
+    fn config() -> NftSetConfig {
+        NftSetConfig { dns: Some("trusted".into()), ..Default::default() }
+    }
+
+    #[test]
+    fn test_element_args() {
+        let set = NftSet::new(&config(), false).unwrap();
+        let args = set.element_args("proxy4", &["1.1.1.1".parse().unwrap(), "8.8.8.8".parse().unwrap()]);
+        assert_eq!(args.join(" "), "add element inet https.nat proxy4 { 1.1.1.1 timeout 3600s, 8.8.8.8 timeout 3600s }");
+        let set = NftSet::new(&config(), true).unwrap();
+        assert_eq!(set.element_args("proxy4", &[])[3], "https.tproxy");
+        assert!(NftSet::new(&NftSetConfig { table: Some("https.nat".into()), ..config() }, false).is_err());
+        assert!(NftSet::new(&NftSetConfig { timeout: 0, ..config() }, false).is_err());
+        assert!(NftSet::new(&NftSetConfig::default(), false).is_err());
+    }
+
+    #[test]
+    fn test_pending() {
+        let set = NftSet::new(&NftSetConfig { timeout: 60, ..config() }, false).unwrap();
+        let ip: IpAddr = "1.1.1.1".parse().unwrap();
+        let now = Instant::now();
+        assert_eq!(set.pending(&[ip], now), vec![ip]);
+        // 写入失败时不记录，下次重试
+        assert_eq!(set.pending(&[ip], now), vec![ip]);
+        set.mark(&[ip], now);
+        // 持续使用的地址不会延长记录的时间，过期前重新写入
+        assert!(set.pending(&[ip], now + Duration::from_secs(10)).is_empty());
+        assert!(set.pending(&[ip], now + Duration::from_secs(20)).is_empty());
+        assert_eq!(set.pending(&[ip], now + Duration::from_secs(30)), vec![ip]);
+    }
+}
@@ -1,8 +1,9 @@
+use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
 use std::io::Error;
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
-use std::os::unix::prelude::AsRawFd;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::os::unix::prelude::{AsRawFd, RawFd};
 
 use anyhow::anyhow;
 use log::{debug, trace};
@@ -10,17 +11,74 @@ use tokio::net::{TcpSocket, TcpStream};
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// 本机所有网卡上的地址，包括回环地址
+pub fn local_addresses() -> Result<HashSet<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(anyhow!("unable to get interface addresses: {}",Error::last_os_error()));
+    }
+    let mut addrs = HashSet::new();
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addrs.insert(IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.insert(IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// 给 socket 设置 IP_TRANSPARENT 和 IPV6_TRANSPARENT，用于 TPROXY 模式，需要 CAP_NET_ADMIN 权限
+pub fn set_transparent(fd: RawFd, v6: bool) -> Result<()> {
+    let on: libc::c_int = 1;
+    let mut options = vec![(libc::SOL_IP, libc::IP_TRANSPARENT)];
+    if v6 {
+        options.push((libc::SOL_IPV6, libc::IPV6_TRANSPARENT));
+    }
+    for (level, name) in options {
+        let ret = unsafe {
+            libc::setsockopt(fd, level, name,
+                             &on as *const libc::c_int as *const libc::c_void,
+                             mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(anyhow!("unable to set transparent socket option: {}",Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
 pub async fn connect(addr: SocketAddr, fwmark: u16) -> Result<TcpStream> {
     let socket = match addr {
         SocketAddr::V4(..) => TcpSocket::new_v4()?,
         SocketAddr::V6(..) => TcpSocket::new_v6()?,
     };
 
+    set_mark(socket.as_raw_fd(), fwmark);
+    Ok(socket.connect(addr).await?)
+}
+
+/// 给 socket 设置 SO_MARK，fwmark 为 0 时不做任何处理
+pub fn set_mark(fd: RawFd, fwmark: u16) {
     #[cfg(unix)]
     if fwmark > 0 {
         let m = fwmark as u32;
         let ret = unsafe {
-            libc::setsockopt(socket.as_raw_fd(),
+            libc::setsockopt(fd,
                              libc::SOL_SOCKET,
                              libc::SO_MARK,
                              &m as *const u32 as *const libc::c_void,
@@ -31,9 +89,9 @@ pub async fn connect(addr: SocketAddr, fwmark: u16) -> Result<TcpStream> {
             debug!("setsockopt error:{}",Error::last_os_error());
         }
     }
-    return Ok(socket.connect(addr).await?);
 }
 
+#[derive(Clone, PartialEq)]
 pub enum Target {
     Hostname(String),
     IPv4(SocketAddrV4),
@@ -65,6 +123,7 @@ impl Target {
             }
         })
     }
+    #[allow(dead_code)]
     pub async fn connect(&self) -> Result<TcpStream> {
         let addr: SocketAddr = self.to_addr()?;
         trace!("connecting: {} ...",&addr);
@@ -82,8 +141,8 @@ impl Target {
 impl From<SocketAddr> for Target {
     fn from(addr: SocketAddr) -> Self {
         match addr {
-            SocketAddr::V4(v4) => Target::IPv4(v4.into()),
-            SocketAddr::V6(v6) => Target::IPv6(v6.into()),
+            SocketAddr::V4(v4) => Target::IPv4(v4),
+            SocketAddr::V6(v6) => Target::IPv6(v6),
         }
     }
 }
\ No newline at end of file
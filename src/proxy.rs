@@ -1,116 +1,343 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use log::{debug, trace, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use crate::{combine, get_http_domain, get_https_domain, get_target_address, RuleEngine};
+use crate::{combine, get_http_domain, RuleEngine};
+use crate::http::{Body, RequestHead};
+use crate::utils::{Buffer, ClientHelloInfo, get_client_hello, just_hostname, MAX_HEADER_SIZE, read_client_hello};
+use crate::config::EchPolicy;
+use crate::dns::Resolver;
+use crate::nftset::NftSet;
 use crate::prelude::*;
+use crate::{proxy_protocol, sniff, socks};
+use crate::upstream::{Outbound, Upstreams};
+use crate::learn::{is_poisoned, Learner};
+
+/// 等待客户端发送 ClientHello 的最长时间
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+/// 自动识别协议时等待客户端发送数据的最长时间
+const DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// 识别其他协议中的主机名的最长时间
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 默认的 ECH 公开名称
+const ECH_PUBLIC_NAMES: &[&str] = &["cloudflare-ech.com"];
+
+/// 入站连接的类型
+#[derive(Clone, Copy, Debug)]
+pub enum Inbound {
+    /// 通过 nftables 重定向的 http 流量
+    Http,
+    /// 通过 nftables 重定向的 https 流量
+    Https,
+    /// 客户端显式配置的 HTTP 代理
+    Forward,
+    /// 客户端显式配置的 socks5 代理
+    Socks,
+    /// 根据客户端发送的第一个字节判断协议
+    Auto,
+}
 
 #[derive(Clone)]
 pub struct Proxy {
-    addr: SocketAddr,
+    /// --proxy 指定的上游服务器
+    pub upstreams: Upstreams,
+    /// 配置文件中的上游服务器组
+    pub groups: Arc<HashMap<String, Upstreams>>,
     pub fwmark: u16,
+    pub nftset: Option<NftSet>,
+    pub ech: EchPolicy,
+    /// ECH 服务器的公开名称，用于区分真正的 ECH 和 GREASE ECH
+    pub ech_public_names: Arc<Vec<String>>,
+    /// HTTP 请求头的最大长度
+    pub max_header_size: usize,
+    /// socks5 入站的用户名和密码，为空时不需要认证
+    pub socks_users: Arc<HashMap<String, String>>,
+    /// 自动学习直连失败的域名，为空时不学习
+    pub learner: Option<Learner>,
     r: RuleEngine,
+    dns: Resolver,
 }
 
 impl Proxy {
-    pub fn new(server: &str, r: RuleEngine) -> Result<Self> {
-        let proxy_address: SocketAddr = match server.parse() {
-            Ok(addr) => addr,
+    pub fn new(upstreams: Upstreams, r: RuleEngine, dns: Resolver) -> Self {
+        Proxy { upstreams, groups: Default::default(), fwmark: 0, nftset: None, ech: EchPolicy::Sni, ech_public_names: Arc::new(ECH_PUBLIC_NAMES.iter().map(|n| n.to_string()).collect()), max_header_size: MAX_HEADER_SIZE, socks_users: Default::default(), learner: None, r, dns }
+    }
+    /// 根据规则选择通过代理或者直接连接目标，peer 为客户端地址
+    async fn open(&self, target: &Target, hello: Option<&ClientHelloInfo>, peer: SocketAddr) -> Result<Outbound> {
+        self.open_with(target, hello, peer, &[]).await
+    }
+    /// 连接目标并发送客户端已经发送的第一段数据。路由规则设置了 fallback 时，
+    /// 连接失败或者在 fallback.within 时间内被重置，改用另一种方式重新连接并发送这段数据
+    async fn open_with(&self, target: &Target, hello: Option<&ClientHelloInfo>, peer: SocketAddr, initial: &[u8]) -> Result<Outbound> {
+        let proxy = self.r.check(target, hello).await;
+        let Some(fallback) = self.r.fallback(target, hello) else {
+            if let (false, Some(learner), Target::Hostname(hostname)) = (proxy, &self.learner, target) {
+                if self.r.action(target, hello).is_none() {
+                    return self.learn(learner, hostname, target, hello, peer, initial).await;
+                }
+            }
+            return self.try_open(target, proxy, hello, peer, initial, None).await;
+        };
+        let first = timeout(Duration::from_millis(fallback.timeout), async {
+            self.try_open(target, proxy, hello, peer, initial, Some(Duration::from_millis(fallback.within))).await
+        }).await.unwrap_or_else(|_| Err(anyhow!("connection timed out")));
+        match first {
+            Ok(stream) => Ok(stream),
             Err(err) => {
-                return Err(anyhow!("proxy server address format error:{} {}",server,err));
+                debug!("[fallback] {} proxy:{} failed: {}, retry proxy:{}",target,proxy,err,!proxy);
+                self.try_open(target, !proxy, hello, peer, initial, None).await
+            }
+        }
+    }
+    /// 按规则文件直连的域名连接超时、发送 initial 后被重置或者解析到被污染的地址时通过代理重试，
+    /// 重试成功时把可注册的域名加入代理列表
+    async fn learn(&self, learner: &Learner, hostname: &str, target: &Target, hello: Option<&ClientHelloInfo>, peer: SocketAddr, initial: &[u8]) -> Result<Outbound> {
+        let hostname = just_hostname(hostname.to_string());
+        let direct = timeout(learner.timeout, async {
+            let stream = self.try_open(target, false, hello, peer, initial, Some(learner.within)).await?;
+            let ip = stream.peer_addr()?.ip();
+            if is_poisoned(&hostname, ip) {
+                return Err(anyhow!("resolved to loopback or unspecified address: {}",ip));
             }
+            Ok(stream)
+        }).await.unwrap_or_else(|_| Err(anyhow!("connection timed out")));
+        let reason = match direct {
+            Ok(stream) => match self.verify_direct(learner, &hostname, &stream).await {
+                Ok(()) => return Ok(stream),
+                Err(err) => err.to_string(),
+            },
+            Err(err) => err.to_string(),
         };
-        Ok(Proxy::from_addr(proxy_address, r))
+        debug!("[learn] {} direct failed: {}, retry via proxy",target,reason);
+        let stream = self.try_open(target, true, hello, peer, initial, Some(learner.within)).await?;
+        let domain = learner.psl.registrable_domain(&hostname).unwrap_or(&hostname);
+        self.r.insert(domain).await;
+        learner.record(domain, &hostname, &reason);
+        Ok(stream)
     }
-    pub fn from_addr(proxy_address: SocketAddr, r: RuleEngine) -> Self {
-        Proxy { addr: proxy_address, fwmark: 0, r }
+    /// 配置了可信的 DNS 服务器时，检查直连的地址是否在它的解析结果中，不在时视为 DNS 被污染。
+    /// 可信的服务器解析失败时无法判断，按直连成功处理
+    async fn verify_direct(&self, learner: &Learner, hostname: &str, stream: &Outbound) -> Result<()> {
+        let Some(name) = learner.dns.as_deref() else { return Ok(()); };
+        let ip = stream.peer_addr()?.ip().to_canonical();
+        match self.dns.resolve(self, hostname, Some(name)).await {
+            Ok(ips) if !ips.iter().any(|i| i.to_canonical() == ip) => {
+                Err(anyhow!("address {} not returned by trusted dns: {:?}",ip,ips))
+            }
+            Ok(_) => Ok(()),
+            Err(err) => {
+                debug!("[learn] unable to verify {} via {}: {}",hostname,name,err);
+                Ok(())
+            }
+        }
+    }
+    /// 连接目标并发送 initial，within 不为空时等待服务器的响应，这段时间内连接被关闭视为失败。
+    /// 直连并且路由规则设置了 proxy_protocol 时先发送 PROXY protocol 头部
+    async fn try_open(&self, target: &Target, proxy: bool, hello: Option<&ClientHelloInfo>, peer: SocketAddr, initial: &[u8], within: Option<Duration>) -> Result<Outbound> {
+        let mut stream = self.open_via(target, proxy, hello).await?;
+        if !proxy && self.r.proxy_protocol(target, hello) {
+            let authority = match target {
+                Target::Hostname(hostname) => Some(just_hostname(hostname.clone())),
+                _ => None,
+            };
+            let header = proxy_protocol::encode_v2(peer, stream.peer_addr()?, authority.as_deref());
+            trace!("proxy protocol: {} ==> {}",peer,target);
+            stream.write_all(&header).await?;
+        }
+        stream.write_all(initial).await?;
+        if let Some(within) = within.filter(|w| !w.is_zero() && !initial.is_empty()) {
+            match timeout(within, stream.peek(&mut [0u8; 1])).await {
+                Ok(Ok(0)) => return Err(anyhow!("connection closed by peer")),
+                Ok(Err(err)) => return Err(err.into()),
+                // 收到响应，或者服务器响应较慢
+                Ok(Ok(_)) | Err(_) => {}
+            }
+        }
+        Ok(stream)
+    }
+    async fn open_via(&self, target: &Target, proxy: bool, hello: Option<&ClientHelloInfo>) -> Result<Outbound> {
+        if !proxy {
+            return Ok(self.connect(target).await?.into());
+        }
+        if let (Some(set), Target::Hostname(hostname)) = (&self.nftset, target) {
+            let (p, set) = (self.clone(), set.clone());
+            let hostname = just_hostname(hostname.clone());
+            tokio::spawn(async move {
+                match p.dns.resolve(&p, hostname.as_str(), Some(set.dns.as_str())).await {
+                    Ok(mut ips) => {
+                        ips.retain(|ip| !is_poisoned(&hostname, *ip));
+                        set.add(&ips).await
+                    }
+                    Err(err) => debug!("nftset: {}",err),
+                }
+            });
+        }
+        self.dial_with(target, hello).await
     }
     async fn connect(&self, target: &Target) -> Result<TcpStream> {
-        Ok(target.connect_fwmark(self.fwmark).await?)
+        let Target::Hostname(hostname) = target else {
+            return target.connect_fwmark(self.fwmark).await;
+        };
+        let (hostname, port) = hostname.split_once(':')
+            .ok_or(anyhow!("missing port: {}",hostname))?;
+        let port: u16 = port.parse()?;
+        let ips = self.dns.resolve(self, hostname, self.r.dns_server(hostname)).await?;
+        let mut last_err = anyhow!("unable to resolve domain name: {}",hostname);
+        for ip in ips {
+            trace!("connecting: {}:{} ({}) ...",ip,port,hostname);
+            match connect(SocketAddr::new(ip, port), self.fwmark).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+    pub async fn dial(&self, target: &Target) -> Result<Outbound> {
+        self.dial_with(target, None).await
     }
-    async fn dial(&self, target: &Target) -> Result<TcpStream> {
+    /// 通过代理连接目标，按路由规则选择上游服务器组
+    async fn dial_with(&self, target: &Target, hello: Option<&ClientHelloInfo>) -> Result<Outbound> {
         trace!("proxy: {}",target);
-        let mut connect = connect(self.addr, self.fwmark).await?;
-        connect.write_all(&[0x05u8, 0x01, 0x00]).await?;
-        let mut bb = [0u8; 2];
-        connect.read_exact(&mut bb).await?;
-        if (bb[0] as u16) << 8 | (bb[1] as u16) != 0x0500 {
-            return Err(anyhow!("proxy server type not supported"));
-        }
-        match target {
-            Target::IPv4(ip) => {
-                let mut data = [0x05u8, 0x01, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff, (ip.port() >> 8) as u8, ip.port() as u8];
-                data[4..8].copy_from_slice(&ip.ip().octets());
-                connect.write_all(&data).await?;
-            }
-            Target::IPv6(ip) => {
-                let mut data: [u8; 22] = [0u8; 22];
-                data[0] = 0x05;
-                data[1] = 0x01;
-                data[2] = 0x00;
-                data[3] = 0x04;
-                data[4..20].copy_from_slice(&ip.ip().octets());
-                data[20] = (ip.port() >> 8) as u8;
-                data[21] = ip.port() as u8;
-                connect.write_all(&data).await?;
-            }
-            Target::Hostname(hostname) => {
-                let (hostname, port) = hostname.split_once(":").unwrap();
-                let port: u16 = port.parse()?;
-                trace!("socks5 hostname:{} port:{}",hostname,port);
-                let mut data = [0u8; 280];
-                data[0] = 0x05;
-                data[1] = 0x01;
-                data[2] = 0x00;
-                data[3] = 0x03;
-                data[4] = hostname.len() as u8;
-                data[5..5 + hostname.len()].copy_from_slice(hostname.as_bytes());
-                data[5 + hostname.len()] = (port >> 8) as u8;
-                data[6 + hostname.len()] = port as u8;
-                connect.write_all(&data[..7 + hostname.len()]).await?;
-            }
-        }
-        let mut b4 = [0u8; 4];
-        connect.read_exact(&mut b4).await?;
-        if b4[0] != 0x05 || b4[1] != 0x00 {
-            return Err(anyhow!("proxy server authentication is not supported"));
-        }
-        match b4[3] {
-            0x01 => {
-                let mut b6 = [0u8; 6];
-                connect.read_exact(&mut b6).await?;
-            }
-            0x03 => {
-                let mut b6 = [0u8; 260];
-                let n = connect.read_u8().await?;
-                connect.read_exact(&mut b6[..n as usize]).await?;
-            }
-            0x04 => {
-                let mut b6 = [0u8; 18];
-                connect.read_exact(&mut b6).await?;
-            }
-            _ => {
-                let mut b6 = [0u8; 6];
-                connect.read_exact(&mut b6).await?;
-            }
+        Ok(self.request(socks::CMD_CONNECT, target, hello).await?.0)
+    }
+
+    /// 选择的上游服务器组能否转发 UDP。经过跳板时控制连接的对端是第一跳，
+    /// UDP 数据报无法经过跳板的隧道，不能使用 UDP ASSOCIATE
+    pub fn can_associate(&self, target: &Target, hello: Option<&ClientHelloInfo>) -> bool {
+        self.upstreams(target, hello).via.is_empty()
+    }
+
+    /// 向代理服务器发起 UDP ASSOCIATE，返回控制连接和代理服务器的 UDP 转发地址，
+    /// 控制连接关闭后代理服务器会停止转发，target 和 hello 用于选择上游服务器组
+    pub async fn associate(&self, target: &Target, hello: Option<&ClientHelloInfo>) -> Result<(Outbound, SocketAddr)> {
+        let upstreams = self.upstreams(target, hello);
+        if !upstreams.via.is_empty() {
+            return Err(anyhow!("udp relay is not supported through via hops: {}",upstreams.name));
+        }
+        let unspecified: Target = SocketAddr::from(([0, 0, 0, 0], 0)).into();
+        let (control, bind) = self.request_via(upstreams, socks::CMD_UDP_ASSOCIATE, &unspecified).await?;
+        let relay = match bind {
+            Target::IPv4(addr) => SocketAddr::V4(addr),
+            Target::IPv6(addr) => SocketAddr::V6(addr),
+            Target::Hostname(hostname) => return Err(anyhow!("unsupported udp relay address: {}",hostname)),
+        };
+        if relay.ip().is_unspecified() {
+            let server = control.peer_addr()?;
+            return Ok((control, SocketAddr::new(server.ip(), relay.port())));
         }
+        Ok((control, relay))
+    }
 
+    /// 发送 socks5 请求，返回连接和代理服务器绑定的地址
+    async fn request(&self, cmd: u8, target: &Target, hello: Option<&ClientHelloInfo>) -> Result<(Outbound, Target)> {
+        self.request_via(self.upstreams(target, hello), cmd, target).await
+    }
+    async fn request_via(&self, upstreams: &Upstreams, cmd: u8, target: &Target) -> Result<(Outbound, Target)> {
+        let mut connect = upstreams.connect(self.fwmark, target).await?;
+        trace!("socks5 command:{} target:{}",cmd,target);
+        let bind = socks::command(&mut *connect, cmd, target).await?;
+        Ok((connect, bind))
+    }
+    /// 路由规则选择的上游服务器组，未设置时使用默认的服务器
+    fn upstreams(&self, target: &Target, hello: Option<&ClientHelloInfo>) -> &Upstreams {
+        self.r.group(target, hello).and_then(|g| self.groups.get(g)).unwrap_or(&self.upstreams)
+    }
 
-        Ok(connect)
+    /// 是否需要通过代理连接目标
+    pub async fn should_proxy(&self, target: &Target, hello: Option<&ClientHelloInfo>) -> bool {
+        self.r.check(target, hello).await
     }
 
-    pub async fn handler_https(&self, client: TcpStream) {
-        let peer = match client.peer_addr() {
-            Ok(addr) => addr,
-            Err(err) => {
-                warn!("get peer fault:{}",err);
-                return;
+    /// 处理入站连接，peer 为客户端地址，dst 为连接的原始目标地址，客户端直接连接时为 None
+    pub async fn handle(&self, inbound: Inbound, client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
+        match inbound {
+            Inbound::Http => self.handler_http(client, peer, dst).await,
+            Inbound::Https => self.handler_https(client, peer, dst).await,
+            Inbound::Forward => self.handler_forward(client, peer).await,
+            Inbound::Socks => self.handler_socks(client, peer).await,
+            Inbound::Auto => self.handler_auto(client, peer, dst).await,
+        }
+    }
+
+    /// 读取第一个字节判断协议：0x16 为 TLS，0x05 为 socks5，大写字母为 HTTP 请求方法。
+    /// 没有原始目标地址时说明客户端是直接连接的，HTTP 请求按 HTTP 代理处理。
+    /// 其他协议尝试识别其中的主机名，客户端一直不发送数据（例如服务器先发送数据的协议）时，按原始目标地址转发
+    pub async fn handler_auto(&self, client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
+        // 加载了 conntrack 时，直接连接监听端口的客户端也能通过 SO_ORIGINAL_DST 取到地址，就是监听的地址本身
+        let local = client.local_addr().ok().map(|a| SocketAddr::new(a.ip().to_canonical(), a.port()));
+        let dst = dst.filter(|d| Some(*d) != local);
+        let mut peek = [0u8; 16];
+        let n = match timeout(DETECT_TIMEOUT, client.peek(&mut peek)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return,
+            Ok(Ok(n)) => n,
+            Err(_) => return self.handler_tcp(client, peer, dst).await,
+        };
+        // "PROXY " 也是大写字母开始
+        if n >= 6 && proxy_protocol::is_header(&peek[..n]) {
+            return self.handler_sniff(client, peer, dst).await;
+        }
+        match peek[0] {
+            0x16 => self.handler_https(client, peer, dst).await,
+            // 被重定向的连接是发往其他地址的，以 0x05 开始的不是发给这里的 socks5 请求
+            0x05 if dst.is_none() => self.handler_socks(client, peer).await,
+            b'A'..=b'Z' if dst.is_some() => self.handler_http(client, peer, dst).await,
+            b'A'..=b'Z' => self.handler_forward(client, peer).await,
+            _ => self.handler_sniff(client, peer, dst).await,
+        }
+    }
+
+    /// 使用 sniff::SNIFFERS 识别 TLS、HTTP 之外的协议中的主机名，按主机名和原始目标端口分流，
+    /// 识别失败时按原始目标地址转发
+    pub async fn handler_sniff(&self, mut client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
+        let Some(dst) = dst else {
+            debug!("[sniff] get target address error: {} ",peer);
+            return;
+        };
+        let mut buf = Vec::new();
+        let target = match timeout(SNIFF_TIMEOUT, sniff::sniff(&mut client, &mut buf)).await {
+            Ok(Some((name, target))) => {
+                let target = target.set_port(dst.port());
+                debug!("[sniff] {} <==> {} ({}) protocol:{}",peer,target,dst,name);
+                target
             }
+            _ => {
+                debug!("[sniff] {} <==> {} unknown protocol",peer,dst);
+                dst.into()
+            }
+        };
+        match self.open_with(&target, None, peer, &buf).await {
+            Ok(remote) => combine(client, remote).await,
+            Err(err) => warn!("[sniff] connection failed:{} ==> {}, err: {}",peer,target,err),
+        }
+    }
+
+    /// 不解析内容，按原始目标地址转发
+    pub async fn handler_tcp(&self, client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
+        let Some(dst) = dst else {
+            debug!("[tcp] get target address error: {} ",peer);
+            return;
         };
-        let dst = get_target_address(&client);
+        let target: Target = dst.into();
+        debug!("[tcp] {} <==> {}",peer,target);
+        match self.open(&target, None, peer).await {
+            Ok(remote) => combine(client, remote).await,
+            Err(err) => warn!("[tcp] connection failed:{} ==> {}, err: {}",peer,target,err),
+        }
+    }
+
+    pub async fn handler_https(&self, mut client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
         let port: u16 = match dst {
             Some(addr) => {
                 debug!("[https] {} <==> {}",peer,&addr);
@@ -121,16 +348,29 @@ impl Proxy {
                 433
             }
         };
-        let mut buf = [0u8; 1024];
-        let size = client.peek(&mut buf).await.unwrap();
-        let target: Target = match get_https_domain(&buf[..size]) {
-            Ok(t) => {
-                t.set_port(port)
+        let mut raw = Vec::new();
+        let hello = match timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(&mut client, &mut raw)).await {
+            Ok(hello) => hello,
+            Err(_) => Err(anyhow!("timeout reading client hello")),
+        };
+        let hello = hello.and_then(|_| get_client_hello(&raw));
+        if let Ok(info) = &hello {
+            debug!("[https] {} sni:{} alpn:{:?} ja3:{} ja4:{}",peer,info.sni.as_deref().unwrap_or("-"),info.alpn,info.ja3(),info.ja4());
+        }
+        let ech = matches!(&hello, Ok(info) if info.is_ech(&self.ech_public_names));
+        if ech {
+            debug!("[https] {} encrypted client hello, outer name: {}",peer,hello.as_ref().ok().and_then(|i| i.sni.as_deref()).unwrap_or("-"));
+        }
+        let target: Target = match (&hello, dst) {
+            (_, Some(addr)) if ech && self.ech != EchPolicy::Sni => addr.into(),
+            (Ok(ClientHelloInfo { sni: Some(hostname), .. }), _) => {
+                Target::Hostname(hostname.clone()).set_port(port)
             }
-            Err(err) => {
+            _ => {
                 match dst {
                     Some(addr) => { addr.into() }
                     None => {
+                        let err = hello.err().unwrap_or(anyhow!("https request hostname not found"));
                         warn!("[https] unknown connect address:{} err:{}",peer,err);
                         return;
                     }
@@ -138,30 +378,24 @@ impl Proxy {
             }
         };
 
-        let connection = if self.r.check_target(&target).await {
-            self.dial(&target).await
+        let connection = if ech && self.ech == EchPolicy::Proxy {
+            match self.open_via(&target, true, hello.as_ref().ok()).await {
+                Ok(mut remote) => remote.write_all(&raw).await.map(|_| remote).map_err(Into::into),
+                Err(err) => Err(err),
+            }
         } else {
-            self.connect(&target).await
+            self.open_with(&target, hello.as_ref().ok(), peer, &raw).await
         };
         match connection {
-            Ok(remote) => {
-                combine(client, remote).await;
-            }
+            Ok(remote) => combine(client, remote).await,
             Err(err) => {
-                warn!("[http] connection failed:{} ==> {}, err: {}",peer,target,err)
+                warn!("[https] connection failed:{} ==> {}, err: {}",peer,target,err)
             }
         }
     }
 
-    pub async fn handler_http(&self, mut client: TcpStream) {
-        let peer = match client.peer_addr() {
-            Ok(addr) => addr,
-            Err(err) => {
-                warn!("get peer fault:{}",err);
-                return;
-            }
-        };
-        let (buf, target) = match get_http_domain(&mut client).await {
+    pub async fn handler_http(&self, mut client: TcpStream, peer: SocketAddr, dst: Option<SocketAddr>) {
+        let (buf, target, head) = match get_http_domain(&mut client, dst, self.max_header_size).await {
             Ok(v) => {
                 debug!("[http] {} <==> {}",peer,&v.1);
                 v
@@ -171,25 +405,452 @@ impl Proxy {
                 return;
             }
         };
-        let connection = if self.r.check_target(&target).await {
-            self.dial(&target).await
-        } else {
-            self.connect(&target).await
+        let mut remote = match self.open(&target, None, peer).await {
+            Ok(remote) => remote,
+            Err(err) => {
+                warn!("[http] connection failed:{} ==> {}, err: {}",peer,target,err);
+                return;
+            }
         };
-        match connection {
-            Ok(mut remote) => {
-                if cfg!(debug_assertions) {
-                    if let Ok(request) = std::str::from_utf8(buf.bytes()) {
-                        trace!("http request:{}",request.replace("\r\n","\\r\\n"));
-                    }
-                }
-                remote.write_all(buf.bytes()).await.unwrap();
+        let Some(head) = head else {
+            // 不是能够解析的 HTTP 请求，原样转发
+            if remote.write_all(buf.bytes()).await.is_ok() {
                 combine(client, remote).await;
             }
+            return;
+        };
+        let port = dst.map(|addr| addr.port()).unwrap_or(80);
+        if let Err(err) = self.relay_http(client, peer, remote, buf, head, target, port, false).await {
+            debug!("[http] {} relay: {}",peer,err);
+        }
+    }
+
+    /// HTTP 代理，支持 CONNECT 和 absolute-form 请求
+    pub async fn handler_forward(&self, mut client: TcpStream, peer: SocketAddr) {
+        let mut buf = Buffer::new(self.max_header_size);
+        let head = buf.read_head(&mut client).await.and_then(|head| {
+            let connect = head.method == "CONNECT";
+            if !connect && !head.uri.get(..7).is_some_and(|s| s.eq_ignore_ascii_case("http://")) {
+                return Err(anyhow!("not a proxy request: {} {}",head.method,head.uri));
+            }
+            let target = head.target(if connect { 443 } else { 80 })?;
+            Ok((head, target))
+        });
+        let (head, target) = match head {
+            Ok(v) => v,
+            Err(err) => {
+                debug!("[forward] {} bad request: {}",peer,err);
+                let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n").await;
+                return;
+            }
+        };
+        debug!("[forward] {} {} {}",peer,head.method,target);
+        let mut remote = match self.open(&target, None, peer).await {
+            Ok(remote) => remote,
             Err(err) => {
-                warn!("[http] connection failed:{} ==> {}, err: {}",peer,target,err)
+                warn!("[forward] connection failed:{} ==> {}, err: {}",peer,target,err);
+                let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n").await;
+                return;
             }
+        };
+        if head.method != "CONNECT" {
+            if let Err(err) = self.relay_http(client, peer, remote, buf, head, target, 80, true).await {
+                debug!("[forward] {} relay: {}",peer,err);
+            }
+            return;
+        }
+        if client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await.is_err() {
+            return;
+        }
+        if remote.write_all(buf.pending()).await.is_ok() {
+            combine(client, remote).await;
         }
     }
+
+    /// socks5 代理，域名请求直接按规则分流，不需要读取请求内容
+    pub async fn handler_socks(&self, mut client: TcpStream, peer: SocketAddr) {
+        let unspecified: Target = SocketAddr::from(([0, 0, 0, 0], 0)).into();
+        let target = match socks::accept(&mut client, &self.socks_users).await {
+            Ok((socks::CMD_CONNECT, target)) => target,
+            Ok((cmd, _)) => {
+                debug!("[socks] {} command not supported: {}",peer,cmd);
+                let _ = socks::reply(&mut client, socks::REP_COMMAND_NOT_SUPPORTED, &unspecified).await;
+                return;
+            }
+            Err(err) => {
+                debug!("[socks] {} handshake failed: {}",peer,err);
+                return;
+            }
+        };
+        debug!("[socks] {} <==> {}",peer,target);
+        match self.open(&target, None, peer).await {
+            Ok(remote) => {
+                let bind = remote.local_addr().map(Target::from).unwrap_or(unspecified);
+                if socks::reply(&mut client, socks::REP_SUCCEEDED, &bind).await.is_ok() {
+                    combine(client, remote).await;
+                }
+            }
+            Err(err) => {
+                warn!("[socks] connection failed:{} ==> {}, err: {}",peer,target,err);
+                let rep = match err.downcast_ref::<std::io::Error>() {
+                    Some(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => socks::REP_CONNECTION_REFUSED,
+                    _ => socks::REP_GENERAL_FAILURE,
+                };
+                let _ = socks::reply(&mut client, rep, &unspecified).await;
+            }
+        }
+    }
+
+    /// 逐个解析同一个连接上的请求，目标主机变化时等待已经发出的请求的响应完成后重新连接。
+    /// forward 为 true 时客户端把这里当作 HTTP 代理，请求头需要改写成 origin-form
+    #[allow(clippy::too_many_arguments)]
+    async fn relay_http(&self, client: TcpStream, peer: SocketAddr, remote: Outbound, mut buf: Buffer, head: RequestHead, mut target: Target, port: u16, forward: bool) -> Result<()> {
+        let (mut reader, writer) = client.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let (mut upstream, mut pending, mut downstream) = relay_response(remote, writer.clone());
+        let mut request = head.body().map(|body| (head, body));
+        loop {
+            let (head, body) = match request {
+                Ok(request) => request,
+                Err(err) if forward => return Err(err),
+                Err(err) => {
+                    // 请求无法解析时，剩余的数据原样转发给当前的目标
+                    debug!("[http] {} {}, forward to {}",peer,err,target);
+                    let _ = pending.send(Pending::Raw);
+                    upstream.write_all(buf.bytes()).await?;
+                    tokio::io::copy(&mut reader, &mut upstream).await?;
+                    break;
+                }
+            };
+            if cfg!(debug_assertions) {
+                if let Ok(request) = std::str::from_utf8(buf.head()) {
+                    trace!("http request:{}",request.replace("\r\n","\\r\\n"));
+                }
+            }
+            if forward {
+                upstream.write_all(&head.to_origin_form()).await?;
+            } else {
+                upstream.write_all(buf.head()).await?;
+            }
+            match body {
+                Body::Length(n) => {
+                    let _ = pending.send(Pending::Response(head.method));
+                    buf.copy_exact(n, &mut reader, &mut upstream).await?
+                }
+                Body::Chunked => {
+                    let _ = pending.send(Pending::Response(head.method));
+                    buf.copy_chunked(&mut reader, &mut upstream).await?
+                }
+                Body::Tunnel => {
+                    let _ = pending.send(Pending::Raw);
+                    upstream.write_all(buf.pending()).await?;
+                    tokio::io::copy(&mut reader, &mut upstream).await?;
+                    break;
+                }
+            }
+            buf.consume();
+            if buf.pending().is_empty() && reader.peek(&mut [0u8; 1]).await? == 0 {
+                break;
+            }
+            let next = match buf.read_head(&mut reader).await.and_then(|h| {
+                if forward && h.method == "CONNECT" {
+                    return Err(anyhow!("unexpected CONNECT request"));
+                }
+                Ok((h.target(port)?, h.body()?, h))
+            }) {
+                Ok((next, body, h)) => {
+                    request = Ok((h, body));
+                    next
+                }
+                Err(err) => {
+                    request = Err(err);
+                    continue;
+                }
+            };
+            if next == target && !downstream.is_finished() {
+                continue;
+            }
+            if next != target {
+                debug!("[http] {} switch {} ==> {}",peer,target,next);
+            }
+            // 只等待已经发出的请求的响应，响应按长度分帧，不需要等服务器关闭连接
+            drop(pending);
+            let _ = (&mut downstream).await;
+            let _ = upstream.shutdown().await;
+            let remote = self.open(&next, None, peer).await?;
+            (upstream, pending, downstream) = relay_response(remote, writer.clone());
+            target = next;
+        }
+        let _ = upstream.shutdown().await;
+        drop(pending);
+        let _ = downstream.await;
+        Ok(())
+    }
+}
+
+/// 已经发给服务器、等待响应的请求
+enum Pending {
+    /// 请求的方法，HEAD 请求的响应没有响应体
+    Response(String),
+    /// 之后的数据不再是 HTTP 响应，原样转发到连接关闭
+    Raw,
+}
+
+/// 将服务器的响应转发给客户端，多个服务器连接共用同一个客户端写入端。
+/// 每个请求对应一个完整的响应，所有请求的响应都转发完成后结束
+fn relay_response(remote: Outbound, writer: Arc<Mutex<OwnedWriteHalf>>) -> (WriteHalf<Outbound>, UnboundedSender<Pending>, JoinHandle<()>) {
+    let (mut r, w) = tokio::io::split(remote);
+    let (tx, mut rx) = unbounded_channel();
+    let handle = tokio::spawn(async move {
+        let mut buf = Buffer::new(MAX_HEADER_SIZE);
+        while let Some(pending) = rx.recv().await {
+            let mut writer = writer.lock().await;
+            let result = match pending {
+                Pending::Response(method) => copy_response(&mut buf, &method, &mut r, &mut *writer).await,
+                Pending::Raw => copy_raw(&mut buf, &mut r, &mut *writer).await.map(|_| false),
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    debug!("[http] relay response: {}",err);
+                    break;
+                }
+            }
+        }
+    });
+    (w, tx, handle)
 }
 
+/// 转发一个请求的响应，包括之前的 1xx 临时响应。之后的数据不再是 HTTP 响应时返回 false
+async fn copy_response<R, W>(buf: &mut Buffer, method: &str, r: &mut R, w: &mut W) -> Result<bool>
+    where R: AsyncRead + Send + Sync + Unpin, W: AsyncWrite + Unpin {
+    loop {
+        buf.consume();
+        let head = match buf.read_response(r).await {
+            Ok(head) => head,
+            Err(err) => {
+                // 响应头无法解析时，原样转发到连接关闭
+                debug!("[http] {}, forward response",err);
+                w.write_all(buf.bytes()).await?;
+                tokio::io::copy(r, w).await?;
+                return Ok(false);
+            }
+        };
+        w.write_all(buf.head()).await?;
+        match head.body(method) {
+            Body::Length(n) => buf.copy_exact(n, r, w).await?,
+            Body::Chunked => buf.copy_chunked(r, w).await?,
+            Body::Tunnel => {
+                w.write_all(buf.pending()).await?;
+                tokio::io::copy(r, w).await?;
+                return Ok(false);
+            }
+        }
+        if !head.is_informational() {
+            return Ok(true);
+        }
+    }
+}
+
+/// 原样转发缓冲区中剩余的数据和之后的所有数据
+async fn copy_raw<R, W>(buf: &mut Buffer, r: &mut R, w: &mut W) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    w.write_all(buf.pending()).await?;
+    tokio::io::copy(r, w).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::config::{DnsConfig, DnsServerConfig, LearnConfig, Route, Strategy};
+    use crate::dns::Resolver;
+    use crate::learn::Learner;
+    use crate::prelude::Target;
+    use crate::proxy::{copy_response, Proxy};
+    use crate::rule::RuleEngine;
+    use crate::socks;
+    use crate::upstream::{Hop, Upstreams};
+    use crate::utils::{Buffer, MAX_HEADER_SIZE};
+
+    /// 完成握手后原样返回收到的数据的 socks5 服务器
+    async fn echo_socks() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if socks::accept(&mut s, &HashMap::new()).await.is_ok() {
+                        let _ = s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await;
+                        let (mut r, mut w) = s.split();
+                        let _ = tokio::io::copy(&mut r, &mut w).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// 读取第一段数据后等待 delay 再回复 reply，reply 为空时直接关闭连接
+    async fn server(delay: Duration, reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = s.read(&mut [0u8; 1024]).await;
+                    tokio::time::sleep(delay).await;
+                    if !reply.is_empty() {
+                        let _ = s.write_all(reply).await;
+                        let _ = s.read(&mut [0u8; 1024]).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// UDP DNS 服务器，对所有 A 查询返回 answer，其它查询返回空结果
+    async fn dns_server(answer: [u8; 4]) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, client)) = socket.recv_from(&mut buf).await {
+                let mut response = buf[..n].to_vec();
+                response[2] |= 0x80;
+                if response[n - 3] == 1 {
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04]);
+                    response.extend_from_slice(&answer);
+                }
+                let _ = socket.send_to(&response, client).await;
+            }
+        });
+        addr
+    }
+
+    fn proxy(upstream: SocketAddr, routes: Vec<Route>) -> Proxy {
+        let upstreams = Upstreams::new("default", vec![upstream], Strategy::Failover);
+        let r = RuleEngine::from_file(None, None, routes, Default::default(), false).unwrap();
+        Proxy::new(upstreams, r, Resolver::new(&DnsConfig::default()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let socks = echo_socks().await;
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let route: Route = serde_json::from_str(r#"{ "all": true, "fallback": { "timeout": 1000, "within": 200 } }"#).unwrap();
+        let p = proxy(socks, vec![route]);
+
+        // 直连失败，改用代理
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let stream = p.open_with(&closed.into(), None, peer, b"hello").await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), socks);
+
+        // 发送第一段数据后被关闭，通过代理重新发送同样的数据
+        let reset = server(Duration::ZERO, b"").await;
+        let mut stream = p.open_with(&reset.into(), None, peer, b"hello").await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), socks);
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        // 服务器响应慢不算失败
+        let slow = server(Duration::from_millis(400), b"slow").await;
+        let mut stream = p.open_with(&slow.into(), None, peer, b"hello").await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), slow);
+        stream.read_exact(&mut data[..4]).await.unwrap();
+        assert_eq!(&data[..4], b"slow");
+    }
+
+    #[tokio::test]
+    async fn test_learn() {
+        let socks = echo_socks().await;
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let path = std::env::temp_dir().join(format!("harmony-learn-proxy-{}.log", std::process::id()));
+        let cfg = LearnConfig { enabled: true, log: Some(path.to_string_lossy().into()), ..Default::default() };
+        let mut p = proxy(socks, vec![]);
+        p.learner = Some(Learner::new(&cfg, Default::default()).unwrap());
+
+        // 直连失败，通过代理成功后加入代理列表并记录
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let target = Target::Hostname(format!("localhost:{}", closed.port()));
+        assert!(!p.should_proxy(&target, None).await);
+        let mut stream = p.open_with(&target, None, peer, b"hello").await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), socks);
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        assert!(p.should_proxy(&target, None).await);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.contains(" localhost localhost "), "{}", log);
+
+        // 之后直接通过代理连接
+        let stream = p.open_with(&target, None, peer, b"hello").await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), socks);
+
+        // 直连成功，但地址不在可信的 DNS 服务器的解析结果中
+        let direct = server(Duration::ZERO, b"ok").await;
+        let target = Target::Hostname(format!("localhost:{}", direct.port()));
+        for (answer, proxied) in [([127, 0, 0, 2], true), ([127, 0, 0, 1], false)] {
+            let mut dns = DnsConfig::default();
+            dns.servers.insert("trusted".into(), DnsServerConfig::Address(dns_server(answer).await.to_string()));
+            let cfg = LearnConfig { enabled: true, dns: Some("trusted".into()), ..Default::default() };
+            let mut p = proxy(socks, vec![]);
+            p.dns = Resolver::new(&dns).unwrap();
+            p.learner = Some(Learner::new(&cfg, Default::default()).unwrap());
+            let stream = p.open_with(&target, None, peer, b"hello").await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap() == socks, proxied);
+            assert_eq!(p.should_proxy(&target, None).await, proxied);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_response() {
+        // 服务器不关闭连接，每个响应按长度分帧后立即返回
+        let (mut server, mut remote) = tokio::io::duplex(4096);
+        server.write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok\
+            HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n\
+            HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n0\r\n\r\n").await.unwrap();
+        let mut buf = Buffer::new(MAX_HEADER_SIZE);
+        let mut out = Vec::new();
+        assert!(copy_response(&mut buf, "POST", &mut remote, &mut out).await.unwrap());
+        assert_eq!(out, b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        out.clear();
+        assert!(copy_response(&mut buf, "HEAD", &mut remote, &mut out).await.unwrap());
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n");
+        out.clear();
+        assert!(copy_response(&mut buf, "GET", &mut remote, &mut out).await.unwrap());
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n0\r\n\r\n");
+
+        // 没有长度的响应一直转发到连接关闭
+        server.write_all(b"HTTP/1.0 200 OK\r\n\r\nrest").await.unwrap();
+        drop(server);
+        out.clear();
+        assert!(!copy_response(&mut buf, "GET", &mut remote, &mut out).await.unwrap());
+        assert_eq!(out, b"HTTP/1.0 200 OK\r\n\r\nrest");
+        assert_eq!(remote.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_associate_via() {
+        let socks = echo_socks().await;
+        let mut p = proxy(socks, vec![]);
+        let target: Target = "1.1.1.1:443".parse::<SocketAddr>().unwrap().into();
+        assert!(p.can_associate(&target, None));
+        // 经过跳板时 UDP 不能跟随隧道，直接拒绝，不连接服务器
+        p.upstreams.via = std::sync::Arc::new(vec![Hop::parse(&socks.to_string()).unwrap()]);
+        assert!(!p.can_associate(&target, None));
+        let err = p.associate(&target, None).await.err().unwrap();
+        assert!(err.to_string().contains("via"));
+    }
+}
@@ -0,0 +1,274 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::prelude::*;
+use crate::utils::Reader;
+
+/// 等待客户端发送头部的最长时间
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// v2 的 12 字节签名
+pub const SIGNATURE_V2: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// v1 头部的最大长度，包括结尾的 CRLF
+const MAX_V1_SIZE: usize = 107;
+/// TLV 类型，客户端请求的主机名，通常来自 SNI 或者 Host
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+
+/// HAProxy PROXY protocol 头部，https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+#[derive(Debug, Default, PartialEq)]
+pub struct Header {
+    /// 真实的客户端地址，LOCAL 命令或者 UNKNOWN 协议时为 None
+    pub src: Option<SocketAddr>,
+    /// 客户端连接的原始目标地址
+    pub dst: Option<SocketAddr>,
+    /// v2 的 PP2_TYPE_AUTHORITY
+    pub authority: Option<String>,
+}
+
+/// 数据是否以 PROXY protocol 头部开始，数据不足时按已有的部分判断
+pub fn is_header(buf: &[u8]) -> bool {
+    let n = buf.len().min(SIGNATURE_V2.len());
+    n > 0 && (buf[..n] == SIGNATURE_V2[..n] || buf[..n.min(6)] == b"PROXY "[..n.min(6)])
+}
+
+/// 解析 v1 或者 v2 头部，返回头部和占用的字节数，数据不足时返回 None
+pub fn parse(buf: &[u8]) -> Result<Option<(Header, usize)>> {
+    if !is_header(buf) {
+        return Err(anyhow!("not a proxy protocol header"));
+    }
+    if buf[0] == b'P' {
+        parse_v1(buf)
+    } else {
+        parse_v2(buf)
+    }
+}
+
+/// 从连接中读取头部，只读取头部本身的字节，不会读取之后的数据
+pub async fn read_header<R>(r: &mut R) -> Result<Header> where R: AsyncRead + Unpin {
+    let mut buf = vec![0u8; 16];
+    // "PROXY UNKNOWN\r\n" 只有 15 字节，先读取能够区分版本的前 6 字节
+    r.read_exact(&mut buf[..6]).await?;
+    if !is_header(&buf[..6]) {
+        return Err(anyhow!("not a proxy protocol header"));
+    }
+    if buf[0] == b'P' {
+        buf.truncate(6);
+        loop {
+            if let Some((header, _)) = parse_v1(&buf)? {
+                return Ok(header);
+            }
+            buf.push(r.read_u8().await?);
+        }
+    }
+    r.read_exact(&mut buf[6..]).await?;
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    buf.resize(16 + len, 0);
+    r.read_exact(&mut buf[16..]).await?;
+    match parse_v2(&buf)? {
+        Some((header, _)) => Ok(header),
+        None => Err(anyhow!("incomplete proxy protocol header")),
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<(Header, usize)>> {
+    let Some(end) = buf.windows(2).take(MAX_V1_SIZE - 1).position(|w| w == b"\r\n") else {
+        if buf.len() >= MAX_V1_SIZE {
+            return Err(anyhow!("proxy protocol v1 header is too long"));
+        }
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(&buf[..end])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let header = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Header::default(),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let (src, dst): (IpAddr, IpAddr) = (src.parse()?, dst.parse()?);
+            if src.is_ipv4() != (*proto == "TCP4") || dst.is_ipv4() != (*proto == "TCP4") {
+                return Err(anyhow!("proxy protocol v1 address family mismatch: {}",line));
+            }
+            Header {
+                src: Some(SocketAddr::new(src, sport.parse()?)),
+                dst: Some(SocketAddr::new(dst, dport.parse()?)),
+                authority: None,
+            }
+        }
+        _ => return Err(anyhow!("invalid proxy protocol v1 header: {}",line)),
+    };
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(Header, usize)>> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    if buf[..12] != SIGNATURE_V2[..] {
+        return Err(anyhow!("invalid proxy protocol v2 signature"));
+    }
+    let mut r = Reader::new(&buf[12..]);
+    let ver_cmd = r.u8()?;
+    let family = r.u8()?;
+    let len = r.u16()? as usize;
+    if ver_cmd >> 4 != 2 {
+        return Err(anyhow!("proxy protocol version not supported: {}",ver_cmd >> 4));
+    }
+    if buf.len() < 16 + len {
+        return Ok(None);
+    }
+    let mut r = Reader::new(&buf[16..16 + len]);
+    let mut header = Header::default();
+    let addresses = match family >> 4 {
+        0x1 => {
+            let src: [u8; 4] = r.bytes(4)?.try_into()?;
+            let dst: [u8; 4] = r.bytes(4)?.try_into()?;
+            Some((IpAddr::from(Ipv4Addr::from(src)), IpAddr::from(Ipv4Addr::from(dst))))
+        }
+        0x2 => {
+            let src: [u8; 16] = r.bytes(16)?.try_into()?;
+            let dst: [u8; 16] = r.bytes(16)?.try_into()?;
+            Some((IpAddr::from(Ipv6Addr::from(src)), IpAddr::from(Ipv6Addr::from(dst))))
+        }
+        // AF_UNIX 的地址没有用处，跳过
+        0x3 => {
+            r.bytes(216)?;
+            None
+        }
+        _ => None,
+    };
+    if let Some((src, dst)) = addresses {
+        let (sport, dport) = (r.u16()?, r.u16()?);
+        // LOCAL 命令是负载均衡器自己发起的连接，忽略其中的地址
+        if ver_cmd & 0x0f == 0x01 {
+            header.src = Some(SocketAddr::new(src, sport));
+            header.dst = Some(SocketAddr::new(dst, dport));
+        }
+    }
+    while !r.is_empty() {
+        let ty = r.u8()?;
+        let value = r.vec16()?;
+        if ty == PP2_TYPE_AUTHORITY {
+            header.authority = Some(String::from_utf8(value.to_vec())?);
+        }
+    }
+    Ok(Some((header, 16 + len)))
+}
+
+/// 构造 PROXY 命令的 v2 头部，authority 不为空时附带 PP2_TYPE_AUTHORITY。
+/// 两个地址的协议族不同时都转换为 IPv6 地址
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr, authority: Option<&str>) -> Vec<u8> {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (src, dst) = (canonical(src), canonical(dst));
+    let mut body = Vec::new();
+    let family = match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            body.extend_from_slice(&src.ip().octets());
+            body.extend_from_slice(&dst.ip().octets());
+            0x11
+        }
+        _ => {
+            let v6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            body.extend_from_slice(&v6(src).octets());
+            body.extend_from_slice(&v6(dst).octets());
+            0x21
+        }
+    };
+    body.extend_from_slice(&src.port().to_be_bytes());
+    body.extend_from_slice(&dst.port().to_be_bytes());
+    if let Some(authority) = authority.filter(|a| a.len() <= u16::MAX as usize) {
+        body.push(PP2_TYPE_AUTHORITY);
+        body.extend_from_slice(&(authority.len() as u16).to_be_bytes());
+        body.extend_from_slice(authority.as_bytes());
+    }
+    let mut data = SIGNATURE_V2.to_vec();
+    data.extend_from_slice(&[0x21, family]);
+    data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    data.extend_from_slice(&body);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::proxy_protocol::{encode_v2, Header, is_header, parse, read_header};
+
+    #[test]
+    fn test_v1() {
+        let data = b"PROXY TCP4 192.168.1.2 1.2.3.4 50000 443\r\nGET";
+        let (header, n) = parse(data).unwrap().unwrap();
+        assert_eq!(n, data.len() - 3);
+        assert_eq!(header.src, Some("192.168.1.2:50000".parse().unwrap()));
+        assert_eq!(header.dst, Some("1.2.3.4:443".parse().unwrap()));
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::2 2001:db8::1 50000 443\r\n").unwrap().unwrap();
+        assert_eq!(header.dst, Some("[2001:db8::1]:443".parse().unwrap()));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap(), (Header::default(), 15));
+        assert!(parse(b"PROXY TCP4 192.168.1.2").unwrap().is_none());
+        assert!(parse(b"PROXY TCP4 2001:db8::2 1.2.3.4 50000 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.1.2 1.2.3.4 50000\r\n").is_err());
+        assert!(parse(&[&b"PROXY "[..], &[b'x'; 200]].concat()).is_err());
+        assert!(parse(b"POST / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let src = "192.168.1.2:50000".parse().unwrap();
+        let dst = "1.2.3.4:5222".parse().unwrap();
+        let data = encode_v2(src, dst, Some("example.com"));
+        assert!(is_header(&data[..4]));
+        for i in 1..data.len() {
+            assert!(parse(&data[..i]).unwrap().is_none());
+        }
+        let (header, n) = parse(&data).unwrap().unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(header, Header { src: Some(src), dst: Some(dst), authority: Some("example.com".into()) });
+
+        let data = encode_v2("[2001:db8::2]:50000".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap(), None);
+        let (header, _) = parse(&data).unwrap().unwrap();
+        assert_eq!(header.dst, Some("[2001:db8::1]:443".parse().unwrap()));
+        assert!(header.authority.is_none());
+        // 协议族不同
+        let data = encode_v2(src, "[2001:db8::1]:443".parse().unwrap(), None);
+        let (header, _) = parse(&data).unwrap().unwrap();
+        assert_eq!(header.src, Some("[::ffff:192.168.1.2]:50000".parse().unwrap()));
+        let data = encode_v2("[::ffff:192.168.1.2]:50000".parse().unwrap(), dst, None);
+        assert_eq!(data[13], 0x11);
+
+        // LOCAL 命令
+        let mut data = encode_v2(src, dst, None);
+        data[12] = 0x20;
+        assert_eq!(parse(&data).unwrap().unwrap().0, Header::default());
+        // 长度不足以容纳地址
+        let mut data = encode_v2(src, dst, None);
+        data[15] = 4;
+        assert!(parse(&data).is_err());
+        // 签名的后 6 字节不匹配
+        let mut data = encode_v2(src, dst, None);
+        data[8] = b'X';
+        assert!(parse(&data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let data = b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n";
+        let mut r = &data[..];
+        assert_eq!(read_header(&mut r).await.unwrap(), Header::default());
+        assert_eq!(r, b"GET / HTTP/1.1\r\n");
+
+        let src = "192.168.1.2:50000".parse().unwrap();
+        let dst = "1.2.3.4:443".parse().unwrap();
+        let mut data = encode_v2(src, dst, None);
+        data.extend_from_slice(b"\x16\x03\x01");
+        let mut r = &data[..];
+        assert_eq!(read_header(&mut r).await.unwrap().dst, Some(dst));
+        assert_eq!(r, b"\x16\x03\x01");
+
+        assert!(read_header(&mut &b"\x16\x03\x01\x02\x00\x01\x00"[..]).await.is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 1.2.3.4"[..]).await.is_err());
+        let mut data = encode_v2(src, dst, None);
+        data[8] = b'X';
+        assert!(read_header(&mut &data[..]).await.is_err());
+    }
+}
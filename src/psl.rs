@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use crate::prelude::*;
+
+/// 没有加载列表时使用的常见二级公共后缀，例如 co.uk、com.cn
+const SECOND_LEVEL_SUFFIXES: &[&str] = &["ac", "co", "com", "edu", "gov", "net", "org", "ne", "or", "go"];
+
+/// Public Suffix List，格式见 https://publicsuffix.org/list/ ，
+/// 为空时粗略估计：一般只有顶级域名是公共后缀，国家顶级域名下的常见二级后缀也算
+#[derive(Default)]
+pub struct PublicSuffixList {
+    rules: HashSet<String>,
+    /// `*.ck` 这样的通配规则，保存为 `ck`
+    wildcards: HashSet<String>,
+    /// `!www.ck` 这样的例外规则，保存为 `www.ck`
+    exceptions: HashSet<String>,
+}
+
+impl PublicSuffixList {
+    pub fn from_file(filename: &str) -> Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(filename)?))
+    }
+    pub fn parse(data: &str) -> Self {
+        let mut list = PublicSuffixList::default();
+        for line in data.lines() {
+            let Some(rule) = line.split_whitespace().next() else { continue; };
+            if rule.starts_with("//") {
+                continue;
+            }
+            let rule = rule.to_lowercase();
+            if let Some(rule) = rule.strip_prefix("*.") {
+                list.wildcards.insert(rule.to_string());
+            } else if let Some(rule) = rule.strip_prefix('!') {
+                list.exceptions.insert(rule.to_string());
+            } else {
+                list.rules.insert(rule);
+            }
+        }
+        list
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.wildcards.is_empty() && self.exceptions.is_empty()
+    }
+    /// 主机名的公共后缀包含几级
+    fn suffix_labels(&self, hostname: &str) -> usize {
+        let hostname = hostname.to_lowercase();
+        let labels: Vec<&str> = hostname.split('.').collect();
+        let n = labels.len();
+        if self.is_empty() {
+            return match labels.as_slice() {
+                [.., sld, tld] if tld.len() == 2 && SECOND_LEVEL_SUFFIXES.contains(sld) => 2,
+                _ => 1,
+            };
+        }
+        let suffix = |i: usize| labels[i..].join(".");
+        if let Some(i) = (0..n).find(|&i| self.exceptions.contains(&suffix(i))) {
+            return n - i - 1;
+        }
+        (0..n).find(|&i| self.rules.contains(&suffix(i)) || (i + 1 < n && self.wildcards.contains(&suffix(i + 1))))
+            .map(|i| n - i)
+            .unwrap_or(1)
+    }
+    /// 主机名本身是否为公共后缀，例如 co.uk
+    pub fn is_public_suffix(&self, hostname: &str) -> bool {
+        let hostname = hostname.trim_end_matches('.');
+        hostname.split('.').count() <= self.suffix_labels(hostname)
+    }
+    /// 可注册的域名，即公共后缀再加一级，例如 a.b.example.co.uk => example.co.uk，
+    /// 主机名本身是公共后缀时返回 None
+    pub fn registrable_domain<'a>(&self, hostname: &'a str) -> Option<&'a str> {
+        let hostname = hostname.trim_end_matches('.');
+        let n = self.suffix_labels(hostname) + 1;
+        let labels: Vec<&str> = hostname.rsplitn(n + 1, '.').collect();
+        if labels.len() < n {
+            return None;
+        }
+        let len = labels[..n].iter().map(|l| l.len()).sum::<usize>() + n - 1;
+        Some(&hostname[hostname.len() - len..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::psl::PublicSuffixList;
+
+    #[test]
+    fn test_builtin() {
+        let psl = PublicSuffixList::default();
+        assert_eq!(psl.registrable_domain("www.google.com"), Some("google.com"));
+        assert_eq!(psl.registrable_domain("a.b.example.co.uk"), Some("example.co.uk"));
+        assert_eq!(psl.registrable_domain("www.example.com.cn."), Some("example.com.cn"));
+        assert_eq!(psl.registrable_domain("www.co.com"), Some("co.com"));
+        assert_eq!(psl.registrable_domain("example.com"), Some("example.com"));
+        assert_eq!(psl.registrable_domain("co.uk"), None);
+        assert!(psl.is_public_suffix("co.uk"));
+        assert!(psl.is_public_suffix("com"));
+        assert!(!psl.is_public_suffix("google.com"));
+    }
+
+    #[test]
+    fn test_list() {
+        let psl = PublicSuffixList::parse("// comment\ncom\nuk\nco.uk\n*.ck\n!www.ck\ngithub.io\n");
+        assert_eq!(psl.registrable_domain("a.b.Example.co.uk"), Some("Example.co.uk"));
+        assert_eq!(psl.registrable_domain("x.user.github.io"), Some("user.github.io"));
+        assert_eq!(psl.registrable_domain("a.b.foo.ck"), Some("b.foo.ck"));
+        assert_eq!(psl.registrable_domain("a.www.ck"), Some("www.ck"));
+        assert_eq!(psl.registrable_domain("example.org"), Some("example.org"));
+        assert!(psl.is_public_suffix("github.io"));
+        assert!(psl.is_public_suffix("foo.ck"));
+        assert!(!psl.is_public_suffix("www.ck"));
+        assert!(!psl.is_public_suffix("google.com"));
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::anyhow;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::aead::quic::{AES_128, HeaderProtectionKey};
+use ring::hkdf::{self, HKDF_SHA256, KeyType, Prk, Salt};
+
+use crate::prelude::*;
+use crate::utils::{ClientHelloInfo, MAX_CLIENT_HELLO, parse_client_hello, Reader};
+
+pub const VERSION_1: u32 = 0x00000001;
+pub const VERSION_2: u32 = 0x6b3343cf;
+
+/// Initial 包密钥的公开 salt，RFC 9001 5.2
+const SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+/// RFC 9369 3.3.1
+const SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93,
+    0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+/// 判断数据报是否像一个 QUIC Initial 包，只检查明文的头部
+pub fn is_initial(datagram: &[u8]) -> bool {
+    let (Some(first), Some(version)) = (datagram.first(), datagram.get(1..5)) else {
+        return false;
+    };
+    let version = u32::from_be_bytes([version[0], version[1], version[2], version[3]]);
+    first & 0xc0 == 0xc0 && initial_type(version) == Some((first >> 4) & 0x03)
+}
+
+/// 不同版本中 Initial 包的类型值
+fn initial_type(version: u32) -> Option<u8> {
+    match version {
+        VERSION_1 => Some(0),
+        VERSION_2 => Some(1),
+        _ => None,
+    }
+}
+
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label，RFC 8446 7.1
+fn expand_label(prk: &Prk, label: &[u8], len: usize) -> Result<Vec<u8>> {
+    let length = (len as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let info: [&[u8]; 5] = [&length, &label_len, b"tls13 ", label, &[0]];
+    let mut out = vec![0u8; len];
+    prk.expand(&info, Len(len))
+        .and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| anyhow!("hkdf expand failed"))?;
+    Ok(out)
+}
+
+/// 客户端 Initial 包的密钥
+pub struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    /// 根据版本和客户端选择的 Destination Connection ID 计算密钥，RFC 9001 5.2
+    pub fn client(version: u32, dcid: &[u8]) -> Result<InitialKeys> {
+        let (salt, prefix): (&[u8], &[u8]) = match version {
+            VERSION_1 => (&SALT_V1, b"quic "),
+            VERSION_2 => (&SALT_V2, b"quicv2 "),
+            _ => return Err(anyhow!("quic version not supported: {:#x}",version)),
+        };
+        let initial = Salt::new(HKDF_SHA256, salt).extract(dcid);
+        let secret = expand_label(&initial, b"client in", 32)?;
+        let secret = Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+        let label = |name: &[u8]| [prefix, name].concat();
+        let key = expand_label(&secret, &label(b"key"), 16)?;
+        let iv = expand_label(&secret, &label(b"iv"), 12)?;
+        let hp = expand_label(&secret, &label(b"hp"), 16)?;
+        Ok(InitialKeys {
+            key: LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key).map_err(|_| anyhow!("invalid quic key"))?),
+            iv: iv.try_into().map_err(|_| anyhow!("invalid quic iv"))?,
+            hp: HeaderProtectionKey::new(&AES_128, &hp).map_err(|_| anyhow!("invalid quic hp key"))?,
+        })
+    }
+
+    /// 去掉头部保护并解密，packet 为完整的包，pn_offset 为包号的位置，返回解密后的载荷
+    fn open<'a>(&self, packet: &'a mut [u8], pn_offset: usize) -> Result<&'a [u8]> {
+        let sample = packet.get(pn_offset + 4..pn_offset + 20)
+            .ok_or(anyhow!("quic packet is too short"))?;
+        let mask = self.hp.new_mask(sample).map_err(|_| anyhow!("quic header protection failed"))?;
+        packet[0] ^= mask[0] & 0x0f;
+        let pn_len = (packet[0] & 0x03) as usize + 1;
+        let mut nonce = self.iv;
+        for i in 0..pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+            // 客户端最初几个包的包号很小，截断的包号就是完整的包号
+            nonce[12 - pn_len + i] ^= packet[pn_offset + i];
+        }
+        let (header, payload) = packet.split_at_mut(pn_offset + pn_len);
+        let plain = self.key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(&header[..]), payload)
+            .map_err(|_| anyhow!("quic initial packet decryption failed"))?;
+        Ok(plain)
+    }
+}
+
+/// 从客户端的 Initial 包中收集 CRYPTO 帧，得到 ClientHello。
+/// 较大的 ClientHello（例如包含后量子密钥交换）会分布在多个数据报中
+#[derive(Default)]
+pub struct ClientHelloSniffer {
+    dcid: Vec<u8>,
+    /// CRYPTO 帧的偏移和数据
+    frames: Vec<(u64, Vec<u8>)>,
+}
+
+impl ClientHelloSniffer {
+    /// 添加一个数据报，ClientHello 完整时返回解析结果，不是 QUIC Initial 包时返回错误
+    pub fn push(&mut self, datagram: &[u8]) -> Result<Option<ClientHelloInfo>> {
+        let mut i = 0;
+        while i < datagram.len() {
+            i += self.push_packet(&datagram[i..])?;
+        }
+        self.client_hello()
+    }
+
+    /// 处理一个长头部的包，返回包的长度
+    fn push_packet(&mut self, data: &[u8]) -> Result<usize> {
+        let mut r = Reader::new(data);
+        let first = r.u8()?;
+        if first & 0x80 == 0 {
+            // 短头部的包一定在数据报的最后
+            return Ok(data.len());
+        }
+        let version = u32::from_be_bytes(r.bytes(4)?.try_into()?);
+        let Some(initial) = initial_type(version) else {
+            return Err(anyhow!("quic version not supported: {:#x}",version));
+        };
+        let dcid = r.vec8()?;
+        r.vec8()?; // scid
+        let is_initial = (first >> 4) & 0x03 == initial;
+        if is_initial {
+            let n = r.varint()? as usize;
+            r.bytes(n)?; // token
+        }
+        let length = r.varint()? as usize;
+        let pn_offset = r.position();
+        r.bytes(length)?;
+        let end = r.position();
+        if !is_initial {
+            return Ok(end);
+        }
+        if self.dcid.is_empty() {
+            self.dcid = dcid.to_vec();
+        }
+        let keys = InitialKeys::client(version, &self.dcid)?;
+        let mut packet = data[..end].to_vec();
+        let payload = keys.open(&mut packet, pn_offset)?;
+        self.push_frames(payload)?;
+        Ok(end)
+    }
+
+    fn push_frames(&mut self, payload: &[u8]) -> Result<()> {
+        let mut r = Reader::new(payload);
+        while !r.is_empty() {
+            match r.varint()? {
+                0x00 | 0x01 => {} // PADDING, PING
+                t @ (0x02 | 0x03) => { // ACK
+                    r.varint()?;
+                    r.varint()?;
+                    let count = r.varint()?;
+                    r.varint()?;
+                    for _ in 0..count {
+                        r.varint()?;
+                        r.varint()?;
+                    }
+                    if t == 0x03 {
+                        for _ in 0..3 {
+                            r.varint()?;
+                        }
+                    }
+                }
+                0x06 => { // CRYPTO
+                    let offset = r.varint()?;
+                    let n = r.varint()? as usize;
+                    let data = r.bytes(n)?;
+                    if offset + n as u64 > MAX_CLIENT_HELLO as u64 {
+                        return Err(anyhow!("client hello is too large"));
+                    }
+                    self.frames.push((offset, data.to_vec()));
+                }
+                0x1c => { // CONNECTION_CLOSE
+                    r.varint()?;
+                    r.varint()?;
+                    let n = r.varint()? as usize;
+                    r.bytes(n)?;
+                }
+                t => return Err(anyhow!("unexpected frame in initial packet: {:#x}",t)),
+            }
+        }
+        Ok(())
+    }
+
+    /// 按偏移拼接 CRYPTO 帧，从 0 开始连续的数据包含完整的 ClientHello 时解析
+    fn client_hello(&self) -> Result<Option<ClientHelloInfo>> {
+        let mut frames: Vec<&(u64, Vec<u8>)> = self.frames.iter().collect();
+        frames.sort_by_key(|(offset, _)| *offset);
+        let mut stream: Vec<u8> = Vec::new();
+        for (offset, data) in frames {
+            let offset = *offset as usize;
+            if offset > stream.len() {
+                break;
+            }
+            if offset + data.len() > stream.len() {
+                stream.extend_from_slice(&data[stream.len() - offset..]);
+            }
+        }
+        if stream.len() < 4 {
+            return Ok(None);
+        }
+        if stream[0] != 0x01 {
+            return Err(anyhow!("this is not a client hello message"));
+        }
+        let length = 4 + ((stream[1] as usize) << 16 | (stream[2] as usize) << 8 | (stream[3] as usize));
+        if stream.len() < length {
+            return Ok(None);
+        }
+        Ok(Some(parse_client_hello(&stream[..length])?))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use ring::aead::{Aad, Nonce};
+
+    use crate::quic::{ClientHelloSniffer, InitialKeys, is_initial, VERSION_1, VERSION_2};
+    use crate::utils::tests::{build_client_hello, server_name};
+
+    /// RFC 9001 附录 A 的 Destination Connection ID
+    const DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// 加密一个客户端 Initial 包，包号为 pn，载荷填充到 1200 字节
+    pub fn seal_initial(version: u32, dcid: &[u8], pn: u8, frames: &[u8]) -> Vec<u8> {
+        let keys = InitialKeys::client(version, dcid).unwrap();
+        let mut payload = frames.to_vec();
+        payload.resize(1162 - dcid.len(), 0);
+        let first = if version == VERSION_2 { 0xd0 } else { 0xc0 };
+        let mut packet = vec![first];
+        packet.extend_from_slice(&version.to_be_bytes());
+        packet.push(dcid.len() as u8);
+        packet.extend_from_slice(dcid);
+        packet.extend_from_slice(&[0x00, 0x00]); // scid, token
+        let length = (payload.len() + 1 + 16) as u16 | 0x4000;
+        packet.extend_from_slice(&length.to_be_bytes());
+        let pn_offset = packet.len();
+        packet.push(pn);
+        let mut nonce = keys.iv;
+        nonce[11] ^= pn;
+        let tag = keys.key.seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), Aad::from(&packet[..]), &mut payload).unwrap();
+        packet.extend_from_slice(&payload);
+        packet.extend_from_slice(tag.as_ref());
+        let mask = keys.hp.new_mask(&packet[pn_offset + 4..pn_offset + 20]).unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet
+    }
+
+    /// CRYPTO 帧
+    pub fn crypto_frame(offset: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x06];
+        frame.extend_from_slice(&(offset | 0x4000).to_be_bytes());
+        frame.extend_from_slice(&(data.len() as u16 | 0x4000).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn test_initial_keys() {
+        // RFC 9001 A.1 和 A.2
+        let keys = InitialKeys::client(VERSION_1, &DCID).unwrap();
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        let mask = keys.hp.new_mask(&hex("d1b1c98dd7689fb8ec11d242b123dc9b")).unwrap();
+        assert_eq!(mask.to_vec(), hex("437b9aec36"));
+        assert!(InitialKeys::client(0xff00001d, &DCID).is_err());
+    }
+
+    #[test]
+    fn test_sniff_client_hello() {
+        let hello = build_client_hello(&[server_name("example.com")]);
+        let handshake = &hello[5..];
+        for version in [VERSION_1, VERSION_2] {
+            // 乱序的 CRYPTO 帧
+            let mut frames = crypto_frame(20, &handshake[20..]);
+            frames.push(0x01);
+            frames.extend_from_slice(&crypto_frame(0, &handshake[..20]));
+            let packet = seal_initial(version, &DCID, 0, &frames);
+            assert!(is_initial(&packet));
+            let info = ClientHelloSniffer::default().push(&packet).unwrap().unwrap();
+            assert_eq!(info.sni.as_deref(), Some("example.com"));
+        }
+    }
+
+    #[test]
+    fn test_sniff_multiple_datagrams() {
+        let hello = build_client_hello(&[server_name("example.com")]);
+        let handshake = &hello[5..];
+        let mut sniffer = ClientHelloSniffer::default();
+        let second = seal_initial(VERSION_1, &DCID, 1, &crypto_frame(30, &handshake[30..]));
+        assert!(sniffer.push(&second).unwrap().is_none());
+        let first = seal_initial(VERSION_1, &DCID, 0, &crypto_frame(0, &handshake[..30]));
+        let info = sniffer.push(&first).unwrap().unwrap();
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_not_initial() {
+        let mut packet = seal_initial(VERSION_1, &DCID, 0, &crypto_frame(0, b"\x01\x00\x00\x10"));
+        assert!(ClientHelloSniffer::default().push(&packet).unwrap().is_none());
+        packet[40] ^= 0xff;
+        assert!(ClientHelloSniffer::default().push(&packet).is_err());
+        assert!(!is_initial(b"\x40\x00\x00\x00\x01"));
+        assert!(!is_initial(b""));
+        assert!(ClientHelloSniffer::default().push(b"\xc0\x00\x00\x00\x01\x08").is_err());
+    }
+}
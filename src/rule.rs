@@ -1,44 +1,60 @@
 use std::{fs, thread};
 use std::ffi::CString;
-use std::fs::File;
-use std::io::{BufRead, Error, Read, Write};
+use std::io::Error;
+use std::net::IpAddr;
 use std::path::Path;
-use std::time::Duration;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use libc::mode_t;
 use log::{debug, info, trace, warn};
-use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::join;
 use tokio::runtime::Builder;
 use tokio::sync::{mpsc, oneshot};
 use tokio::sync::mpsc::Sender;
-use url::quirks::hostname;
 
+use crate::config::{Action, Fallback, Route};
 use crate::prelude::*;
+use crate::psl::PublicSuffixList;
 use crate::rules::Rules;
-use crate::utils::just_hostname;
+use crate::utils::{ClientHelloInfo, just_hostname};
 
 struct Filter {
     rules: Rules,
+    psl: Arc<PublicSuffixList>,
+    /// 加入的域名收缩为可注册的域名
+    normalize: bool,
 }
 
 impl Filter {
     fn insert(&mut self, hostname: &str) {
-        self.rules.add(hostname);
+        let hostname = hostname.trim().trim_end_matches('.');
+        let domain = if self.normalize {
+            self.psl.registrable_domain(hostname).unwrap_or(hostname)
+        } else {
+            hostname
+        };
+        if self.psl.is_public_suffix(domain) {
+            warn!("rule covers the entire public suffix: {}",domain);
+        }
+        self.rules.add(domain);
     }
     fn check_domain(&self, hostname: &str) -> bool {
         self.rules.contain(hostname)
     }
+    /// 检查覆盖了整个公共后缀的规则，例如 co.uk
+    fn lint(&self) {
+        for domain in self.rules.domains() {
+            if self.psl.is_public_suffix(&domain) {
+                warn!("rule covers the entire public suffix: {}",domain);
+            }
+        }
+    }
 }
 
-fn new_rules() -> Filter {
-    Filter { rules: Rules::new() }
-}
-
-fn load_rules(file: &str) -> Result<Filter> {
+fn load_rules(file: &str) -> Result<Rules> {
     debug!("rule file:{}",file);
-    Ok(Filter { rules: Rules::from_file(file)? })
+    Rules::from_file(file)
 }
 
 enum FilterControl {
@@ -47,7 +63,10 @@ enum FilterControl {
 }
 
 #[derive(Clone)]
-pub struct RuleEngine(mpsc::Sender<FilterControl>);
+pub struct RuleEngine {
+    tx: mpsc::Sender<FilterControl>,
+    routes: Arc<Vec<Route>>,
+}
 
 
 impl RuleEngine {
@@ -69,12 +88,12 @@ impl RuleEngine {
         let filename_cp = String::from(filename);
         let path = socket_path.to_path_buf();
         std::thread::spawn(move || {
-            use std::io::{BufRead, BufReader};
-            let mut rt = Builder::new_current_thread().enable_all().build().unwrap();
+            use std::io::BufRead;
+            let rt = Builder::new_current_thread().enable_all().build().unwrap();
             loop {
                 let f = std::fs::File::open(path.as_path())
-                    .expect(format!("cannot open named pipe: {}", filename_cp).as_str());
-                let mut reader = std::io::BufReader::new(f);
+                    .unwrap_or_else(|_| panic!("cannot open named pipe: {}", filename_cp));
+                let reader = std::io::BufReader::new(f);
                 for line in reader.lines() {
                     let Ok(hostname) = line else { break; };
                     let hostname: String =
@@ -93,16 +112,19 @@ impl RuleEngine {
             }
         });
         // 逐行读取文件
-        return Ok(());
+        Ok(())
     }
-    pub fn from_file(filename: Option<String>, sock: Option<String>) -> Result<Self> {
-        let mut filter = if let Some(f) = filename {
+    /// normalize 为 true 时，控制管道加入的域名按 psl 收缩为可注册的域名
+    pub fn from_file(filename: Option<String>, sock: Option<String>, routes: Vec<Route>, psl: Arc<PublicSuffixList>, normalize: bool) -> Result<Self> {
+        let rules = if let Some(f) = filename {
             let r = load_rules(f.as_str())?;
             info!("loading rules completed");
             r
         } else {
-            new_rules()
+            Rules::new()
         };
+        let mut filter = Filter { rules, psl, normalize };
+        filter.lint();
         let (tx, mut rx) = mpsc::channel::<FilterControl>(10);
         let job1 = tokio::spawn(async move {
             while let Some(ctr) = rx.recv().await {
@@ -111,7 +133,7 @@ impl RuleEngine {
                         let _ = reply.send(filter.check_domain(hostname.as_str()));
                     }
                     FilterControl::Insert(hostname) => {
-                        filter.rules.add(hostname.as_str())
+                        filter.insert(hostname.as_str())
                     }
                 }
             }
@@ -127,22 +149,69 @@ impl RuleEngine {
                 .enable_all()
                 .build()
                 .unwrap();
-            rt.block_on(async {
+            let _ = rt.block_on(async {
                 join!(job1,job2)
             });
         });
-        Ok(RuleEngine(tx))
+        Ok(RuleEngine { tx, routes: Arc::new(routes) })
     }
     pub async fn check_target(&self, t: &Target) -> bool {
         let Target::Hostname(hostname) = t else { return false; };
         let (tx, rx) = oneshot::channel::<bool>();
         let msg = FilterControl::Query(just_hostname(hostname.clone()).to_string(), tx);
-        if let Err(e) = self.0.send(msg).await {
+        if let Err(e) = self.tx.send(msg).await {
             warn!("Query domain err:{}",e);
             return false;
         }
         let Ok(result) = rx.await else { return false; };
         trace!("check domain:{} {}",hostname,result);
-        return result;
+        result
+    }
+    /// 根据路由规则和域名规则判断是否需要通过代理连接
+    pub async fn check(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> bool {
+        if let Some(action) = self.action(t, hello) {
+            trace!("route {}: {:?}",t,action);
+            return action == Action::Proxy;
+        }
+        self.check_target(t).await
+    }
+    /// 路由规则指定的连接方式，为空时根据域名规则判断
+    pub fn action(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> Option<Action> {
+        self.target_route(t, hello).and_then(|r| r.action)
+    }
+    /// 把域名加入代理列表，与写入控制管道相同
+    pub async fn insert(&self, hostname: &str) {
+        if let Err(e) = self.tx.send(FilterControl::Insert(hostname.to_string())).await {
+            warn!("Insert domain err:{}",e);
+        }
+    }
+    /// 直连该目标时是否需要发送 PROXY protocol 头部
+    pub fn proxy_protocol(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> bool {
+        self.target_route(t, hello).is_some_and(|r| r.proxy_protocol)
+    }
+    /// 通过代理连接该目标时使用的上游服务器组
+    pub fn group(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> Option<&str> {
+        self.target_route(t, hello).and_then(|r| r.group.as_deref())
+    }
+    /// 连接失败时的重试条件
+    pub fn fallback(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> Option<Fallback> {
+        self.target_route(t, hello).and_then(|r| r.fallback)
+    }
+    /// 目标匹配的第一条路由规则
+    fn target_route(&self, t: &Target, hello: Option<&ClientHelloInfo>) -> Option<&Route> {
+        let (hostname, ip) = match t {
+            Target::Hostname(hostname) => (Some(just_hostname(hostname.clone())), None),
+            Target::IPv4(addr) => (None, Some(IpAddr::V4(*addr.ip()))),
+            Target::IPv6(addr) => (None, Some(IpAddr::V6(*addr.ip()))),
+        };
+        self.route(hostname.as_deref(), ip, hello)
+    }
+    /// 第一条匹配的路由规则
+    pub fn route(&self, hostname: Option<&str>, ip: Option<IpAddr>, hello: Option<&ClientHelloInfo>) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(hostname, ip, hello))
+    }
+    /// 直连时解析该域名使用的 DNS 服务器名称
+    pub fn dns_server(&self, hostname: &str) -> Option<&str> {
+        self.route(Some(hostname), None, None).and_then(|r| r.dns.as_deref())
     }
 }
\ No newline at end of file
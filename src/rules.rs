@@ -11,7 +11,7 @@ pub struct Rules(HashMap<String, Option<Rules>>);
 #[inline]
 fn is_valid_domain(parts: &Vec<&str>) -> bool {
     for part in parts {
-        if part.len() < 1 || part.len() > 63 { // 每个部分的长度必须在 1 到 63 之间
+        if part.is_empty() || part.len() > 63 { // 每个部分的长度必须在 1 到 63 之间
             return false;
         }
         if !part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') { // 每个部分只能包含字母、数字和连字符
@@ -31,7 +31,6 @@ fn is_valid_domain(parts: &Vec<&str>) -> bool {
     true // 如果所有条件都符合，则返回 true
 }
 
-
 impl Rules {
     pub fn new() -> Rules {
         Rules(HashMap::new())
@@ -39,7 +38,7 @@ impl Rules {
     pub fn from_file(filename: &str) -> Result<Rules> {
         let file = std::fs::File::open(filename)?;
         let rules: Rules = serde_json::from_reader(file)?;
-        return Ok(rules);
+        Ok(rules)
     }
     pub fn add(&mut self, domain: &str) {
         let domain = domain.trim().trim_end_matches(".");
@@ -47,29 +46,29 @@ impl Rules {
             warn!("invalid hostname: {}",domain);
             return;
         }
-        let mut list: Vec<&str> = domain.split(".").collect();
+        let list: Vec<&str> = domain.split(".").collect();
         if !is_valid_domain(&list) {
             warn!("invalid hostname: {}",domain);
             return;
         }
-        if list.len() > 0 {
+        if !list.is_empty() {
             info!("add proxy domain: {}",domain);
             self.push(list);
         }
     }
     fn push(&mut self, mut list: Vec<&str>) {
         let Some(k) = list.pop() else { return; };
-        if list.len() == 0 && self.0.contains_key(k) {// 这已经是最后一个元素
+        if list.is_empty() && self.0.contains_key(k) {// 这已经是最后一个元素
             self.0.insert(String::from(k), None);
             return;
         }
-        if let Some(mut m) = self.0.get_mut(k) {
+        if let Some(m) = self.0.get_mut(k) {
             if let Some(r) = m.as_mut() {
                 r.push(list);
             }
             // 如果存在某个 key ，但是这个 key 下面为空 None，那么表示其后面所有子域名都匹配上。
             // 这时候，子域名不需要做插入处理
-        } else if list.len() > 0 {
+        } else if !list.is_empty() {
             let mut r = Rules(HashMap::new());
             r.push(list);
             self.0.insert(String::from(k), Some(r));
@@ -77,10 +76,25 @@ impl Rules {
             self.0.insert(String::from(k), None);
         }
     }
+    /// 规则包含的所有域名，每个域名包含其所有子域名
+    pub fn domains(&self) -> Vec<String> {
+        let mut list = Vec::new();
+        for (k, v) in self.0.iter() {
+            match v {
+                None => list.push(k.clone()),
+                Some(rules) => list.extend(rules.domains().into_iter().map(|d| format!("{}.{}", d, k))),
+            }
+        }
+        list
+    }
     pub fn contain(&self, target: &str) -> bool {
         if target.trim_end_matches(".").ends_with(".cn") {
             return false;
         }
+        self.matches(target)
+    }
+    /// 与 contain 相同，但不排除 .cn 域名
+    pub fn matches(&self, target: &str) -> bool {
         let layers: Vec<&str> = target.trim_end_matches(".").split(".").collect();
         let mut current: &HashMap<String, Option<Rules>> = &self.0;
         for p in layers.iter().rev() {
@@ -152,4 +166,11 @@ mod test {
         assert_eq!(rules.contain("cn"), false);
         assert_eq!(rules.0.len(), 1);
     }
+
+    #[test]
+    fn test_domains() {
+        let mut rules = Rules::new();
+        rules.add("www.google.com");
+        assert_eq!(rules.domains(), vec!["www.google.com"]);
+    }
 }
@@ -0,0 +1,147 @@
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::http::parse_authority;
+use crate::prelude::*;
+use crate::proxy_protocol;
+use crate::utils::{get_https_domain, reassemble_handshake};
+
+/// 从客户端先发送的数据中识别目标主机，数据不足时返回 None，不是这个协议时返回错误。
+/// 返回的端口没有意义，由调用方替换为原始目标端口
+pub type Sniffer = fn(&[u8]) -> Result<Option<Target>>;
+
+/// 按顺序尝试的识别器。SMTP STARTTLS、RTMP 等协议需要服务端先发送数据，
+/// 而连接服务端前必须确定目标，所以无法在这里识别
+pub const SNIFFERS: &[(&str, Sniffer)] = &[
+    ("tls", tls),
+    ("proxy", proxy),
+    ("xmpp", xmpp),
+];
+
+/// 最多缓存的数据长度，超过后不再识别
+const MAX_SNIFF_SIZE: usize = 8 * 1024;
+
+/// 读取客户端数据直到某个识别器得到目标主机，所有识别器都失败、超过长度或者连接关闭时返回 None。
+/// 读取的数据保存在 buf 中，即使调用被超时取消也不会丢失
+pub async fn sniff<R>(r: &mut R, buf: &mut Vec<u8>) -> Option<(&'static str, Target)> where R: AsyncRead + Unpin {
+    let mut candidates: Vec<(&str, Sniffer)> = SNIFFERS.to_vec();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if !buf.is_empty() {
+            let mut i = 0;
+            while i < candidates.len() {
+                match candidates[i].1(buf) {
+                    Ok(Some(target)) => return Some((candidates[i].0, target)),
+                    Ok(None) => i += 1,
+                    Err(_) => {
+                        candidates.remove(i);
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() || buf.len() >= MAX_SNIFF_SIZE {
+            return None;
+        }
+        match r.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+fn tls(buf: &[u8]) -> Result<Option<Target>> {
+    match reassemble_handshake(buf)? {
+        Some(_) => get_https_domain(buf).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// PROXY protocol v2 头部中的 PP2_TYPE_AUTHORITY
+fn proxy(buf: &[u8]) -> Result<Option<Target>> {
+    match proxy_protocol::parse(buf)? {
+        Some((proxy_protocol::Header { authority: Some(authority), .. }, _)) => parse_authority(&authority, 0).map(Some),
+        Some(_) => Err(anyhow!("proxy protocol authority not found")),
+        None => Ok(None),
+    }
+}
+
+/// XMPP 客户端的第一个元素 `<stream:stream to='example.com' ...>`，RFC 6120 4.7.2
+fn xmpp(buf: &[u8]) -> Result<Option<Target>> {
+    const STREAM: &[u8] = b"<stream:stream";
+    let mut rest = buf.trim_ascii_start();
+    if rest.starts_with(b"<?") {
+        match rest.windows(2).position(|w| w == b"?>") {
+            Some(i) => rest = rest[i + 2..].trim_ascii_start(),
+            None => return Ok(None),
+        }
+    }
+    let n = rest.len().min(STREAM.len());
+    if rest[..n] != STREAM[..n] {
+        return Err(anyhow!("not a xmpp stream"));
+    }
+    let Some(end) = rest.iter().position(|&c| c == b'>') else {
+        return Ok(None);
+    };
+    let tag = std::str::from_utf8(&rest[STREAM.len().min(end)..end])?;
+    match attribute(tag, "to") {
+        Some(to) => parse_authority(to, 0).map(Some),
+        None => Err(anyhow!("xmpp stream without to attribute")),
+    }
+}
+
+/// 查找 XML 标签中的属性值，值可以使用单引号或者双引号
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    loop {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+        let (value, remain) = value[1..].split_once(quote)?;
+        if key.split_whitespace().last() == Some(name) {
+            return Some(value);
+        }
+        rest = remain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::prelude::Target;
+    use crate::proxy_protocol::encode_v2;
+    use crate::sniff::{sniff, xmpp};
+
+    #[test]
+    fn test_xmpp() {
+        let data = b"<?xml version='1.0'?>\n<stream:stream from='juliet@im.example.com' to=\"Im.Example.com\" version='1.0' xmlns='jabber:client'>";
+        assert!(matches!(xmpp(data).unwrap(), Some(Target::Hostname(h)) if h == "im.example.com:0"));
+        assert!(xmpp(&data[..10]).unwrap().is_none());
+        assert!(xmpp(&data[..40]).unwrap().is_none());
+        assert!(xmpp(b"<stream:stream version='1.0'>").is_err());
+        assert!(xmpp(b"SSH-2.0-OpenSSH_9.6\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sniff() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let data = encode_v2("192.168.1.2:50000".parse().unwrap(), "1.2.3.4:443".parse().unwrap(), Some("example.com"));
+        client.write_all(&data[..10]).await.unwrap();
+        let task = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            (sniff(&mut server, &mut buf).await, buf)
+        });
+        client.write_all(&data[10..]).await.unwrap();
+        let (result, buf) = task.await.unwrap();
+        let (name, target) = result.unwrap();
+        assert_eq!(name, "proxy");
+        assert_eq!(target.to_string(), "example.com:0");
+        assert_eq!(buf, data);
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        assert!(sniff(&mut server, &mut buf).await.is_none());
+        assert_eq!(buf, b"SSH-2.0-OpenSSH_9.6\r\n");
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::prelude::*;
+use crate::utils::Reader;
+
+pub const VERSION: u8 = 0x05;
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_PASSWORD: u8 = 0x02;
+pub const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// 按 RFC 1928 编码地址，ATYP + 地址 + 端口
+pub fn encode_address(target: &Target) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(22);
+    match target {
+        Target::IPv4(ip) => {
+            data.push(0x01);
+            data.extend_from_slice(&ip.ip().octets());
+            data.extend_from_slice(&ip.port().to_be_bytes());
+        }
+        Target::IPv6(ip) => {
+            data.push(0x04);
+            data.extend_from_slice(&ip.ip().octets());
+            data.extend_from_slice(&ip.port().to_be_bytes());
+        }
+        Target::Hostname(hostname) => {
+            let (hostname, port) = hostname.split_once(':')
+                .ok_or(anyhow!("missing port: {}",hostname))?;
+            let port: u16 = port.parse()?;
+            if hostname.is_empty() || hostname.len() > 255 {
+                return Err(anyhow!("invalid hostname: {}",hostname));
+            }
+            data.push(0x03);
+            data.push(hostname.len() as u8);
+            data.extend_from_slice(hostname.as_bytes());
+            data.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    Ok(data)
+}
+
+/// 读取 ATYP + 地址 + 端口
+pub async fn read_address<R>(r: &mut R) -> Result<Target> where R: AsyncRead + Unpin {
+    match r.read_u8().await? {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            r.read_exact(&mut ip).await?;
+            let port = r.read_u16().await?;
+            Ok(Target::IPv4(SocketAddrV4::new(Ipv4Addr::from(ip), port)))
+        }
+        0x03 => {
+            let n = r.read_u8().await?;
+            let mut hostname = vec![0u8; n as usize];
+            r.read_exact(&mut hostname).await?;
+            let port = r.read_u16().await?;
+            let hostname = String::from_utf8(hostname)?;
+            Ok(Target::Hostname(format!("{}:{}", hostname.to_ascii_lowercase(), port)))
+        }
+        0x04 => {
+            let mut ip = [0u8; 16];
+            r.read_exact(&mut ip).await?;
+            let port = r.read_u16().await?;
+            Ok(Target::IPv6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0)))
+        }
+        atyp => Err(anyhow!("address type not supported: {}",atyp)),
+    }
+}
+
+/// 从数据报中解析 ATYP + 地址 + 端口，返回地址和占用的字节数
+pub fn parse_address(buf: &[u8]) -> Result<(Target, usize)> {
+    let mut r = Reader::new(buf);
+    let target = match r.u8()? {
+        0x01 => {
+            let ip: [u8; 4] = r.bytes(4)?.try_into()?;
+            Target::IPv4(SocketAddrV4::new(Ipv4Addr::from(ip), r.u16()?))
+        }
+        0x03 => {
+            let hostname = String::from_utf8(r.vec8()?.to_vec())?;
+            Target::Hostname(format!("{}:{}", hostname.to_ascii_lowercase(), r.u16()?))
+        }
+        0x04 => {
+            let ip: [u8; 16] = r.bytes(16)?.try_into()?;
+            Target::IPv6(SocketAddrV6::new(Ipv6Addr::from(ip), r.u16()?, 0, 0))
+        }
+        atyp => return Err(anyhow!("address type not supported: {}",atyp)),
+    };
+    Ok((target, r.position()))
+}
+
+/// UDP ASSOCIATE 的数据报格式，RSV + FRAG + 地址 + 数据，RFC 1928 7
+pub fn encode_udp(target: &Target, data: &[u8]) -> Result<Vec<u8>> {
+    let mut packet = vec![0x00, 0x00, 0x00];
+    packet.extend_from_slice(&encode_address(target)?);
+    packet.extend_from_slice(data);
+    Ok(packet)
+}
+
+/// 解析 UDP ASSOCIATE 的数据报，不支持分片
+pub fn decode_udp(packet: &[u8]) -> Result<(Target, &[u8])> {
+    match packet.get(..3) {
+        Some([0x00, 0x00, 0x00]) => {}
+        Some(_) => return Err(anyhow!("udp fragment is not supported")),
+        None => return Err(anyhow!("udp packet is too short")),
+    }
+    let (target, n) = parse_address(&packet[3..])?;
+    Ok((target, &packet[3 + n..]))
+}
+
+/// 服务端握手，完成认证后返回客户端的命令和目标地址。
+/// users 为空时不需要认证，否则要求用户名密码认证，RFC 1929
+pub async fn accept<S>(s: &mut S, users: &HashMap<String, String>) -> Result<(u8, Target)>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    if s.read_u8().await? != VERSION {
+        return Err(anyhow!("socks version not supported"));
+    }
+    let n = s.read_u8().await?;
+    let mut methods = vec![0u8; n as usize];
+    s.read_exact(&mut methods).await?;
+    let method = if users.is_empty() { METHOD_NO_AUTH } else { METHOD_PASSWORD };
+    if !methods.contains(&method) {
+        s.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(anyhow!("no acceptable authentication method"));
+    }
+    s.write_all(&[VERSION, method]).await?;
+    if method == METHOD_PASSWORD {
+        if s.read_u8().await? != 0x01 {
+            return Err(anyhow!("authentication version not supported"));
+        }
+        let n = s.read_u8().await?;
+        let mut username = vec![0u8; n as usize];
+        s.read_exact(&mut username).await?;
+        let n = s.read_u8().await?;
+        let mut password = vec![0u8; n as usize];
+        s.read_exact(&mut password).await?;
+        let username = String::from_utf8_lossy(&username);
+        if users.get(username.as_ref()).map(|p| p.as_bytes()) != Some(password.as_slice()) {
+            s.write_all(&[0x01, 0x01]).await?;
+            return Err(anyhow!("authentication failed: {}",username));
+        }
+        s.write_all(&[0x01, 0x00]).await?;
+    }
+    let mut head = [0u8; 3];
+    s.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(anyhow!("socks version not supported"));
+    }
+    let target = read_address(s).await?;
+    Ok((head[1], target))
+}
+
+/// 客户端握手，只使用不需要认证的方式
+pub async fn greet<S>(s: &mut S) -> Result<()> where S: AsyncRead + AsyncWrite + Unpin {
+    s.write_all(&[VERSION, 0x01, METHOD_NO_AUTH]).await?;
+    let mut reply = [0u8; 2];
+    s.read_exact(&mut reply).await?;
+    if reply != [VERSION, METHOD_NO_AUTH] {
+        return Err(anyhow!("proxy server type not supported"));
+    }
+    Ok(())
+}
+
+/// 握手完成后发送请求，返回服务端绑定的地址
+pub async fn command<S>(s: &mut S, cmd: u8, target: &Target) -> Result<Target> where S: AsyncRead + AsyncWrite + Unpin {
+    let mut data = vec![VERSION, cmd, 0x00];
+    data.extend_from_slice(&encode_address(target)?);
+    s.write_all(&data).await?;
+    let mut head = [0u8; 3];
+    s.read_exact(&mut head).await?;
+    if head[0] != VERSION || head[1] != REP_SUCCEEDED {
+        return Err(anyhow!("proxy server request failed, reply: {}",head[1]));
+    }
+    read_address(s).await
+}
+
+/// 回复客户端的请求，bind 为服务端绑定的地址
+pub async fn reply<W>(w: &mut W, rep: u8, bind: &Target) -> Result<()> where W: AsyncWrite + Unpin {
+    let mut data = vec![VERSION, rep, 0x00];
+    data.extend_from_slice(&encode_address(bind)?);
+    w.write_all(&data).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::prelude::Target;
+    use crate::socks::{accept, CMD_CONNECT, command, decode_udp, encode_address, encode_udp, greet, read_address};
+
+    #[tokio::test]
+    async fn test_address() {
+        for s in ["1.2.3.4:80", "[2001:db8::1]:443"] {
+            let target: Target = s.parse::<std::net::SocketAddr>().unwrap().into();
+            let data = encode_address(&target).unwrap();
+            assert_eq!(read_address(&mut data.as_slice()).await.unwrap().to_string(), s);
+        }
+        let data = encode_address(&Target::Hostname("example.com:443".into())).unwrap();
+        assert_eq!(data[..2], [0x03, 11]);
+        assert_eq!(read_address(&mut data.as_slice()).await.unwrap().to_string(), "example.com:443");
+        assert!(encode_address(&Target::Hostname("example.com".into())).is_err());
+        assert!(read_address(&mut [0x02u8, 0, 0].as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_udp() {
+        let packet = encode_udp(&Target::Hostname("example.com:443".into()), b"data").unwrap();
+        let (target, data) = decode_udp(&packet).unwrap();
+        assert_eq!(target.to_string(), "example.com:443");
+        assert_eq!(data, b"data");
+        let packet = encode_udp(&"1.2.3.4:53".parse::<std::net::SocketAddr>().unwrap().into(), b"").unwrap();
+        assert_eq!(decode_udp(&packet).unwrap().0.to_string(), "1.2.3.4:53");
+        assert!(decode_udp(&[0x00, 0x00, 0x01, 0x01, 1, 2, 3, 4, 0, 53]).is_err());
+        assert!(decode_udp(&[0x00, 0x00, 0x00, 0x01, 1, 2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_password() {
+        let users = HashMap::from([("user".to_string(), "pass".to_string())]);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move { accept(&mut server, &users).await });
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+        client.write_all(b"\x01\x04user\x04pass").await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x01, 0x00]);
+        client.write_all(b"\x05\x01\x00\x03\x0bExample.COM\x01\xbb").await.unwrap();
+        let (cmd, target) = task.await.unwrap().unwrap();
+        assert_eq!(cmd, CMD_CONNECT);
+        assert_eq!(target.to_string(), "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn test_accept_rejected() {
+        let users = HashMap::from([("user".to_string(), "pass".to_string())]);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move { accept(&mut server, &users).await });
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xff]);
+        assert!(task.await.unwrap().is_err());
+
+        let users = HashMap::from([("user".to_string(), "pass".to_string())]);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move { accept(&mut server, &users).await });
+        client.write_all(b"\x05\x01\x02\x01\x04user\x05wrong").await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02, 0x01, 0x01]);
+        assert!(task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move {
+            greet(&mut client).await?;
+            command(&mut client, CMD_CONNECT, &Target::Hostname("example.com:443".into())).await
+        });
+        let (cmd, target) = accept(&mut server, &HashMap::new()).await.unwrap();
+        assert_eq!((cmd, target.to_string().as_str()), (CMD_CONNECT, "example.com:443"));
+        server.write_all(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]).await.unwrap();
+        assert_eq!(task.await.unwrap().unwrap().to_string(), "10.0.0.1:1080");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move {
+            greet(&mut client).await?;
+            command(&mut client, CMD_CONNECT, &Target::Hostname("example.com:443".into())).await
+        });
+        accept(&mut server, &HashMap::new()).await.unwrap();
+        server.write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        assert!(task.await.unwrap().is_err());
+    }
+}
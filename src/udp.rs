@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncReadExt, Interest};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, timeout};
+
+use crate::config::{EchPolicy, QuicPolicy};
+use crate::prelude::*;
+use crate::proxy::Proxy;
+use crate::quic::{ClientHelloSniffer, is_initial};
+use crate::socks;
+use crate::upstream::Outbound;
+use crate::utils::ClientHelloInfo;
+
+/// 等待客户端发送完 ClientHello 的最长时间
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+/// 最多缓存的数据报数量，超过后不再等待 ClientHello
+const MAX_SNIFF_PACKETS: usize = 8;
+/// 每个流在处理前最多排队的数据报数量，超过后丢弃
+const FLOW_QUEUE: usize = 256;
+/// NAT 表中最多同时存在的流数量，超过后丢弃新流的数据报
+const MAX_FLOWS: usize = 4096;
+
+type Flows = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>>>>;
+
+/// TPROXY 模式下的 UDP 转发，按 (客户端地址, 原始目标地址) 维护 NAT 表，
+/// QUIC 连接根据 Initial 包中的 SNI 分流，通过代理时按 quic 的设置转发或者阻断
+pub async fn listen_udp(port: u16, proxy: Proxy, idle: Duration, quic: QuicPolicy) -> Result<JoinHandle<()>> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    socket.set_only_v6(false)?;
+    set_transparent(socket.as_raw_fd(), true)?;
+    setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    setsockopt(socket.as_raw_fd(), libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    let flows: Flows = Default::default();
+    Ok(tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let fd = socket.as_raw_fd();
+            let (n, client, dst) = match socket.async_io(Interest::READABLE, || recv_from_orig_dst(fd, &mut buf)).await {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("[udp] receive failed: {}",err);
+                    continue;
+                }
+            };
+            let Some(dst) = dst else {
+                trace!("[udp] {} original destination not found",client);
+                continue;
+            };
+            let key = (client, dst);
+            let tx = {
+                let mut table = flows.lock().unwrap();
+                match table.get(&key).filter(|tx| !tx.is_closed()) {
+                    Some(tx) => tx.clone(),
+                    None => {
+                        if table.len() >= MAX_FLOWS {
+                            // 先清理已经结束的流，仍然没有空位时丢弃
+                            table.retain(|_, tx| !tx.is_closed());
+                            if table.len() >= MAX_FLOWS {
+                                trace!("[udp] {} ==> {} too many flows, drop packet",client,dst);
+                                continue;
+                            }
+                        }
+                        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+                        table.insert(key, tx.clone());
+                        tokio::spawn(run_flow(proxy.clone(), client, dst, rx, idle, quic, flows.clone()));
+                        tx
+                    }
+                }
+            };
+            if tx.try_send(buf[..n].to_vec()).is_err() {
+                trace!("[udp] {} ==> {} queue is full, drop packet",client,dst);
+            }
+        }
+    }))
+}
+
+async fn run_flow(proxy: Proxy, client: SocketAddr, dst: SocketAddr, mut rx: mpsc::Receiver<Vec<u8>>, idle: Duration, quic: QuicPolicy, flows: Flows) {
+    if let Err(err) = relay_flow(&proxy, client, dst, &mut rx, idle, quic).await {
+        debug!("[udp] {} ==> {} err: {}",client,dst,err);
+    }
+    drop(rx);
+    // 同一个 key 可能已经创建了新的流
+    let mut table = flows.lock().unwrap();
+    if table.get(&(client, dst)).is_some_and(|tx| tx.is_closed()) {
+        table.remove(&(client, dst));
+    }
+    trace!("[udp] {} ==> {} closed, {} flows",client,dst,table.len());
+}
+
+async fn relay_flow(p: &Proxy, client: SocketAddr, dst: SocketAddr, rx: &mut mpsc::Receiver<Vec<u8>>, idle: Duration, quic: QuicPolicy) -> Result<()> {
+    let (pending, hello) = sniff(rx).await;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let ech = hello.as_ref().is_some_and(|h| h.is_ech(&p.ech_public_names));
+    let target: Target = match &hello {
+        Some(ClientHelloInfo { sni: Some(hostname), .. }) if !ech || p.ech == EchPolicy::Sni => {
+            Target::Hostname(format!("{}:{}", hostname, dst.port()))
+        }
+        _ => dst.into(),
+    };
+    let via_proxy = if ech && p.ech == EchPolicy::Proxy {
+        true
+    } else {
+        p.should_proxy(&target, hello.as_ref()).await
+    };
+    // 经过跳板的上游服务器不能转发 UDP，QUIC 按 drop 或者 reject 处理，让浏览器回退到 TCP
+    if via_proxy && hello.is_some() && (quic != QuicPolicy::Relay || !p.can_associate(&target, hello.as_ref())) {
+        debug!("[udp] {} ==> {} ({}) quic blocked",client,target,dst);
+        return block(rx, client, dst, pending, idle, quic == QuicPolicy::Reject).await;
+    }
+    debug!("[udp] {} <==> {} ({}) proxy:{}",client,target,dst,via_proxy);
+    let reply = reply_socket(dst, client)?;
+    if !via_proxy {
+        let upstream = udp_socket(dst, p.fwmark)?;
+        upstream.connect(dst).await?;
+        return relay(rx, &reply, &upstream, pending, idle, None).await;
+    }
+    let (control, relay_addr) = p.associate(&target, hello.as_ref()).await?;
+    let upstream = udp_socket(relay_addr, p.fwmark)?;
+    upstream.connect(relay_addr).await?;
+    relay(rx, &reply, &upstream, pending, idle, Some((control, &target))).await
+}
+
+/// 收集流的前几个数据报，直到得到完整的 ClientHello 或者确定不是 QUIC
+async fn sniff(rx: &mut mpsc::Receiver<Vec<u8>>) -> (Vec<Vec<u8>>, Option<ClientHelloInfo>) {
+    let mut pending = Vec::new();
+    let mut sniffer = ClientHelloSniffer::default();
+    while pending.len() < MAX_SNIFF_PACKETS {
+        let Ok(Some(data)) = timeout(SNIFF_TIMEOUT, rx.recv()).await else {
+            break;
+        };
+        let quic = is_initial(&data);
+        let hello = if quic { sniffer.push(&data) } else { Ok(None) };
+        pending.push(data);
+        match hello {
+            Ok(Some(info)) => return (pending, Some(info)),
+            Ok(None) if quic => {}
+            Ok(None) => break,
+            Err(err) => {
+                trace!("[udp] sniff quic failed: {}",err);
+                break;
+            }
+        }
+    }
+    (pending, None)
+}
+
+/// 在客户端和上游之间转发数据报，socks 不为空时通过 socks5 UDP ASSOCIATE 转发，
+/// 超过 idle 时间没有数据或者 socks5 控制连接关闭时结束
+async fn relay(rx: &mut mpsc::Receiver<Vec<u8>>, reply: &UdpSocket, upstream: &UdpSocket, pending: Vec<Vec<u8>>, idle: Duration, mut socks: Option<(Outbound, &Target)>) -> Result<()> {
+    let encode = |data: &[u8], socks: &Option<(Outbound, &Target)>| match socks {
+        Some((_, target)) => socks::encode_udp(target, data),
+        None => Ok(data.to_vec()),
+    };
+    for data in pending {
+        upstream.send(&encode(&data, &socks)?).await?;
+    }
+    let mut buf = vec![0u8; 65536];
+    let mut b1 = [0u8; 1];
+    let deadline = sleep(idle);
+    tokio::pin!(deadline);
+    loop {
+        let control = async {
+            match socks.as_mut() {
+                Some((control, _)) => control.read(&mut b1).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            data = rx.recv() => {
+                let Some(data) = data else { return Ok(()); };
+                upstream.send(&encode(&data, &socks)?).await?;
+            }
+            n = upstream.recv(&mut buf) => {
+                let data = &buf[..n?];
+                let data = if socks.is_some() { socks::decode_udp(data)?.1 } else { data };
+                reply.send(data).await?;
+            }
+            _ = control => return Err(anyhow!("socks5 control connection closed")),
+            _ = &mut deadline => return Ok(()),
+        }
+        deadline.as_mut().reset(Instant::now() + idle);
+    }
+}
+
+/// 丢弃流的所有数据报直到空闲超时，reject 时对每个数据报回复 ICMP 端口不可达
+async fn block(rx: &mut mpsc::Receiver<Vec<u8>>, client: SocketAddr, dst: SocketAddr, pending: Vec<Vec<u8>>, idle: Duration, reject: bool) -> Result<()> {
+    let (client, dst) = (canonical(client), canonical(dst));
+    let socket = if reject {
+        let protocol = if client.is_ipv4() { Protocol::ICMPV4 } else { Protocol::ICMPV6 };
+        let socket = Socket::new(Domain::for_address(client), Type::RAW, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        Some(socket)
+    } else {
+        None
+    };
+    let send = |data: &[u8]| {
+        if let Some(socket) = &socket {
+            let packet = unreachable(client, dst, data.len());
+            if let Err(err) = socket.send_to(&packet, &SocketAddr::new(client.ip(), 0).into()) {
+                trace!("[udp] {} send icmp failed: {}",client,err);
+            }
+        }
+    };
+    pending.iter().for_each(|data| send(data));
+    while let Ok(Some(data)) = timeout(idle, rx.recv()).await {
+        send(&data);
+    }
+    Ok(())
+}
+
+/// 构造 ICMP 端口不可达报文，附带原始数据报的 IP 头和 UDP 头，
+/// 客户端内核根据其中的地址和端口找到对应的 socket。ICMPv6 的校验和由内核计算
+fn unreachable(client: SocketAddr, dst: SocketAddr, len: usize) -> Vec<u8> {
+    let udp_len = (8 + len).min(u16::MAX as usize) as u16;
+    let mut udp = Vec::with_capacity(8);
+    udp.extend_from_slice(&client.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    match (client.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut ip = vec![0x45, 0x00];
+            ip.extend_from_slice(&(20u16.saturating_add(udp_len)).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0, 0, 64, libc::IPPROTO_UDP as u8, 0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            // type 3 (destination unreachable) code 3 (port unreachable)
+            let mut packet = vec![3, 3, 0, 0, 0, 0, 0, 0];
+            packet.extend_from_slice(&ip);
+            packet.extend_from_slice(&udp);
+            let sum = checksum(&packet);
+            packet[2..4].copy_from_slice(&sum.to_be_bytes());
+            packet
+        }
+        (src, dst) => {
+            let src = match src { IpAddr::V4(ip) => ip.to_ipv6_mapped(), IpAddr::V6(ip) => ip };
+            let dst = match dst { IpAddr::V4(ip) => ip.to_ipv6_mapped(), IpAddr::V6(ip) => ip };
+            // type 1 (destination unreachable) code 4 (port unreachable)
+            let mut packet = vec![1, 4, 0, 0, 0, 0, 0, 0, 0x60, 0, 0, 0];
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[libc::IPPROTO_UDP as u8, 64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+    }
+}
+
+/// RFC 1071 校验和
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// 以原始目标地址为源地址向客户端发送数据的 socket
+fn reply_socket(dst: SocketAddr, client: SocketAddr) -> Result<UdpSocket> {
+    let dst = canonical(dst);
+    let socket = Socket::new(Domain::for_address(dst), Type::DGRAM, None)?;
+    set_transparent(socket.as_raw_fd(), dst.is_ipv6())?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&dst.into())?;
+    socket.connect(&canonical(client).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// 连接上游的 socket，设置 fwmark 避免再次被 TPROXY 规则处理
+fn udp_socket(remote: SocketAddr, fwmark: u16) -> Result<UdpSocket> {
+    let remote = canonical(remote);
+    let socket = Socket::new(Domain::for_address(remote), Type::DGRAM, None)?;
+    set_mark(socket.as_raw_fd(), fwmark);
+    socket.set_nonblocking(true)?;
+    let bind = match remote {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    socket.bind(&bind.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name,
+                         &on as *const libc::c_int as *const libc::c_void,
+                         mem::size_of_val(&on) as libc::socklen_t)
+    };
+    if ret != 0 {
+        return Err(anyhow!("setsockopt error: {}",io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// recvmsg 读取数据报，同时从控制消息中获取 TPROXY 的原始目标地址
+fn recv_from_orig_dst(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let client = sockaddr_to_std(&name).ok_or(io::Error::new(io::ErrorKind::InvalidData, "invalid source address"))?;
+    let mut dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let (level, ty) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if (level == libc::SOL_IP && ty == libc::IP_ORIGDSTADDR) || (level == libc::SOL_IPV6 && ty == libc::IPV6_ORIGDSTADDR) {
+                let mut addr: libc::sockaddr_storage = mem::zeroed();
+                let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize).min(mem::size_of_val(&addr));
+                std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), &mut addr as *mut libc::sockaddr_storage as *mut u8, len);
+                dst = sockaddr_to_std(&addr);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((n as usize, canonical(client), dst.map(canonical)))
+}
+
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::new(IpAddr::V6(ip), u16::from_be(addr.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+
+    use crate::udp::{block, checksum, recv_from_orig_dst, relay, sniff, unreachable};
+
+    #[tokio::test]
+    async fn test_relay_direct() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, peer)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], peer).await;
+            }
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reply = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        reply.connect(client.local_addr().unwrap()).await.unwrap();
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        upstream.connect(echo_addr).await.unwrap();
+
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(b"first".to_vec()).await.unwrap();
+        tx.send(b"second".to_vec()).await.unwrap();
+        // 不是 QUIC 的数据报不需要继续等待
+        let (pending, hello) = sniff(&mut rx).await;
+        assert_eq!(pending, vec![b"first".to_vec()]);
+        assert!(hello.is_none());
+        let task = tokio::spawn(async move {
+            relay(&mut rx, &reply, &upstream, pending, Duration::from_millis(300), None).await
+        });
+        let mut buf = [0u8; 1500];
+        for expect in [&b"first"[..], b"second"] {
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], expect);
+        }
+        // 超过空闲时间后结束
+        assert!(task.await.unwrap().is_ok());
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_recv_without_orig_dst() {
+        use std::os::unix::io::AsRawFd;
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"hello", socket.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 16];
+        let (n, client, dst) = recv_from_orig_dst(socket.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(client, sender.local_addr().unwrap());
+        assert!(dst.is_none());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let client = "192.168.1.2:50000".parse().unwrap();
+        let dst = "1.2.3.4:443".parse().unwrap();
+        let packet = unreachable(client, dst, 1200);
+        assert_eq!(packet.len(), 8 + 20 + 8);
+        assert_eq!(packet[..2], [3, 3]);
+        assert_eq!(checksum(&packet), 0);
+        assert_eq!(checksum(&packet[8..28]), 0);
+        assert_eq!(packet[20..28], [192, 168, 1, 2, 1, 2, 3, 4]);
+        assert_eq!(packet[28..34], [0xc3, 0x50, 0x01, 0xbb, 0x04, 0xb8]);
+
+        let packet = unreachable("[2001:db8::2]:50000".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap(), 1200);
+        assert_eq!(packet.len(), 8 + 40 + 8);
+        assert_eq!(packet[..2], [1, 4]);
+        assert_eq!(packet[12..16], [0x04, 0xb8, 17, 64]);
+        assert_eq!(packet[48..50], [0xc3, 0x50]);
+    }
+
+    #[tokio::test]
+    async fn test_block_drop() {
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(b"second".to_vec()).await.unwrap();
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let dst = "127.0.0.1:443".parse().unwrap();
+        let task = tokio::spawn(async move {
+            block(&mut rx, client, dst, vec![b"first".to_vec()], Duration::from_millis(100), false).await
+        });
+        assert!(task.await.unwrap().is_ok());
+        assert!(tx.is_closed());
+    }
+}
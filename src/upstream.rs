@@ -0,0 +1,482 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, info, trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use crate::config::Strategy;
+use crate::prelude::*;
+use crate::{http, socks};
+use crate::utils::just_hostname;
+
+/// 一个 socks5 上游服务器及其健康状态
+pub struct Upstream {
+    pub addr: SocketAddr,
+    state: Mutex<State>,
+    /// 通过这个服务器建立的、还没有关闭的连接数量
+    active: Arc<AtomicUsize>,
+}
+
+struct State {
+    up: bool,
+    /// 最近一次成功握手的耗时
+    latency: Option<Duration>,
+    /// 最近一次失败的原因
+    error: Option<String>,
+}
+
+/// 连接上游服务器前经过的一跳代理
+#[derive(Clone)]
+pub enum Hop {
+    Socks5(Target),
+    /// 使用 CONNECT 方法的 HTTP 代理
+    Http(Target),
+}
+
+impl Hop {
+    /// 解析 `socks5://host:port`、`http://host:port` 格式的地址，没有前缀时为 socks5
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(rest) = s.strip_prefix("http://") {
+            return Ok(Hop::Http(http::parse_authority(rest.trim_end_matches('/'), 8080)?));
+        }
+        let rest = s.strip_prefix("socks5://").unwrap_or(s);
+        Ok(Hop::Socks5(http::parse_authority(rest.trim_end_matches('/'), 1080)?))
+    }
+    /// 解析一条代理链，第一跳直接连接，必须是 IP 地址，之后的每一跳由上一跳解析
+    pub fn parse_chain(list: &[String]) -> Result<Vec<Self>> {
+        let chain = list.iter().map(|s| Hop::parse(s)).collect::<Result<Vec<_>>>()?;
+        if let Some(Hop::Socks5(Target::Hostname(h)) | Hop::Http(Target::Hostname(h))) = chain.first() {
+            return Err(anyhow!("the first hop must be an ip address: {}",h));
+        }
+        Ok(chain)
+    }
+    fn target(&self) -> &Target {
+        match self {
+            Hop::Socks5(target) | Hop::Http(target) => target,
+        }
+    }
+    /// 在已经连接到这一跳的 stream 上请求连接下一跳 target
+    async fn open(&self, stream: &mut TcpStream, target: &Target) -> Result<()> {
+        match self {
+            Hop::Socks5(_) => {
+                socks::greet(stream).await?;
+                socks::command(stream, socks::CMD_CONNECT, target).await?;
+            }
+            Hop::Http(_) => http::connect(stream, target).await?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for Hop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hop::Socks5(target) => write!(f, "socks5://{}", target),
+            Hop::Http(target) => write!(f, "http://{}", target),
+        }
+    }
+}
+
+/// 一组上游服务器，按 strategy 选择服务器，失败时切换到下一个
+#[derive(Clone)]
+pub struct Upstreams {
+    /// 组名，用于日志和状态文件
+    pub name: String,
+    servers: Arc<Vec<Upstream>>,
+    strategy: Strategy,
+    /// 轮询的计数器
+    next: Arc<AtomicUsize>,
+    /// 连接并完成 socks5 握手的超时时间
+    pub timeout: Duration,
+    /// 连接服务器时依次经过的代理，为空时直接连接服务器
+    pub via: Arc<Vec<Hop>>,
+}
+
+/// 主机和服务器的 FNV-1a 哈希，结果在不同的 Rust 版本和进程之间保持不变，
+/// 最后再混合一次，让相近的输入也能得到分散的结果
+fn hash(host: &str, server: SocketAddr) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    let server = server.to_string();
+    for b in host.bytes().chain([0]).chain(server.bytes()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    // splitmix64 的最后一步
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+impl Upstreams {
+    pub fn new(name: &str, addrs: Vec<SocketAddr>, strategy: Strategy) -> Self {
+        let servers = addrs.into_iter()
+            .map(|addr| Upstream {
+                addr,
+                state: Mutex::new(State { up: true, latency: None, error: None }),
+                active: Default::default(),
+            })
+            .collect();
+        Upstreams { name: name.to_string(), servers: Arc::new(servers), strategy, next: Default::default(), timeout: Duration::from_secs(5), via: Default::default() }
+    }
+
+    /// 按策略排列可用的服务器，全部不可用时使用所有服务器，避免健康检查失误导致完全无法连接
+    fn candidates(&self, target: &Target) -> Vec<&Upstream> {
+        let mut list: Vec<&Upstream> = self.servers.iter().filter(|s| s.is_up()).collect();
+        if list.is_empty() {
+            list = self.servers.iter().collect();
+        }
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed) % list.len().max(1);
+                list.rotate_left(n);
+            }
+            Strategy::LeastConn => list.sort_by_key(|s| s.active.load(Ordering::Relaxed)),
+            Strategy::Latency => list.sort_by_key(|s| s.state.lock().unwrap().latency.unwrap_or(Duration::MAX)),
+            Strategy::Hash => {
+                // rendezvous hashing，服务器增减时只有少量主机会换到其他服务器
+                let host = match target {
+                    Target::Hostname(hostname) => just_hostname(hostname.clone()),
+                    Target::IPv4(addr) => addr.ip().to_string(),
+                    Target::IPv6(addr) => addr.ip().to_string(),
+                };
+                list.sort_by_cached_key(|s| std::cmp::Reverse(hash(&host, s.addr)));
+            }
+        }
+        list
+    }
+
+    /// 按策略依次连接服务器并完成 socks5 握手，连接失败的服务器标记为不可用
+    pub async fn connect(&self, fwmark: u16, target: &Target) -> Result<Outbound> {
+        let mut last_err = anyhow!("no upstream server");
+        for server in self.candidates(target) {
+            match self.handshake(server.addr, fwmark).await {
+                Ok((stream, latency)) => {
+                    trace!("[upstream] {} {} ==> {}",self.name,server.addr,target);
+                    server.update(Ok(latency));
+                    return Ok(Outbound { stream, _lease: Some(Lease::new(server.active.clone())) });
+                }
+                Err(err) => {
+                    server.update(Err(&err));
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn handshake(&self, addr: SocketAddr, fwmark: u16) -> Result<(TcpStream, Duration)> {
+        let start = Instant::now();
+        let stream = timeout(self.timeout, async {
+            let mut stream = self.tunnel(addr, fwmark).await?;
+            socks::greet(&mut stream).await?;
+            Ok::<_, anyhow::Error>(stream)
+        }).await.map_err(|_| anyhow!("timeout connecting to {}",addr))??;
+        Ok((stream, start.elapsed()))
+    }
+
+    /// 依次通过 via 中的每一跳建立到 addr 的隧道，via 为空时直接连接
+    async fn tunnel(&self, addr: SocketAddr, fwmark: u16) -> Result<TcpStream> {
+        let Some(first) = self.via.first() else {
+            return connect(addr, fwmark).await;
+        };
+        let mut stream = first.target().connect_fwmark(fwmark).await?;
+        let next = self.via[1..].iter().map(|h| h.target().clone()).chain(std::iter::once(addr.into()));
+        for (hop, target) in self.via.iter().zip(next) {
+            trace!("[upstream] {} ==> {}",hop,target);
+            hop.open(&mut stream, &target).await
+                .map_err(|err| anyhow!("{} ==> {}: {}",hop,target,err))?;
+        }
+        Ok(stream)
+    }
+
+    /// 检查一个服务器，probe 不为空时还需要通过它成功连接探测目标
+    async fn check(&self, server: &Upstream, probe: Option<&Target>, fwmark: u16) {
+        let result = async {
+            let (mut stream, latency) = self.handshake(server.addr, fwmark).await?;
+            if let Some(probe) = probe {
+                timeout(self.timeout, socks::command(&mut stream, socks::CMD_CONNECT, probe)).await
+                    .map_err(|_| anyhow!("timeout connecting to {}",probe))??;
+            }
+            Ok(latency)
+        }.await;
+        server.update(result.as_ref().copied());
+    }
+
+    /// 每行一个服务器的状态，例如 "default 127.0.0.1:1080 up 3ms 2"，最后一列为活动连接数
+    pub fn status(&self) -> String {
+        self.servers.iter().map(|s| {
+            let active = s.active.load(Ordering::Relaxed);
+            let state = s.state.lock().unwrap();
+            match (state.up, state.latency, &state.error) {
+                (true, Some(latency), _) => format!("{} {} up {}ms {}\n", self.name, s.addr, latency.as_millis(), active),
+                (true, None, _) => format!("{} {} up - {}\n", self.name, s.addr, active),
+                (false, _, error) => format!("{} {} down {}\n", self.name, s.addr, error.as_deref().unwrap_or("-")),
+            }
+        }).collect()
+    }
+}
+
+/// 定期检查所有组的服务器，status 不为空时把状态写入这个文件
+pub fn spawn_check(groups: Vec<Upstreams>, interval: Duration, probe: Option<Target>, fwmark: u16, status: Option<String>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            for upstreams in groups.iter() {
+                for server in upstreams.servers.iter() {
+                    upstreams.check(server, probe.as_ref(), fwmark).await;
+                }
+            }
+            if let Some(file) = &status {
+                let data: String = groups.iter().map(|g| g.status()).collect();
+                if let Err(err) = std::fs::write(file, data) {
+                    debug!("[upstream] unable to write status file {}: {}",file,err);
+                }
+            }
+            sleep(interval).await;
+        }
+    })
+}
+
+/// 记录一个活动连接，释放时减少计数
+struct Lease(Arc<AtomicUsize>);
+
+impl Lease {
+    fn new(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Lease(active)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 出站连接，直接连接或者通过上游服务器建立，通过上游服务器时计入服务器的活动连接数
+pub struct Outbound {
+    stream: TcpStream,
+    _lease: Option<Lease>,
+}
+
+impl From<TcpStream> for Outbound {
+    fn from(stream: TcpStream) -> Self {
+        Outbound { stream, _lease: None }
+    }
+}
+
+impl Deref for Outbound {
+    type Target = TcpStream;
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl DerefMut for Outbound {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+impl AsyncRead for Outbound {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Outbound {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl Upstream {
+    fn is_up(&self) -> bool {
+        self.state.lock().unwrap().up
+    }
+
+    /// 更新状态，状态变化时打印日志
+    fn update(&self, result: std::result::Result<Duration, &anyhow::Error>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(latency) => {
+                if !state.up {
+                    info!("[upstream] {} is up, latency: {}ms",self.addr,latency.as_millis());
+                }
+                *state = State { up: true, latency: Some(latency), error: None };
+            }
+            Err(err) => {
+                if state.up {
+                    warn!("[upstream] {} is down: {}",self.addr,err);
+                }
+                state.up = false;
+                state.error = Some(err.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+    use tokio::net::TcpListener;
+
+    use crate::config::Strategy;
+    use crate::http::parse_authority;
+    use crate::prelude::Target;
+    use crate::socks;
+    use crate::upstream::{hash, Hop, spawn_check, Upstreams};
+
+    /// 只完成握手的 socks5 服务器，返回监听地址
+    async fn server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if socks::accept(&mut s, &HashMap::new()).await.is_ok() {
+                        let _ = s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        // 没有监听的端口
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let good = server().await;
+        let target = Target::Hostname("example.com:443".into());
+        let upstreams = Upstreams::new("default", vec![closed, good], Strategy::Failover);
+        let stream = upstreams.connect(0, &target).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(!upstreams.servers[0].is_up());
+        assert!(upstreams.status().starts_with(&format!("default {} down", closed)));
+        assert!(upstreams.status().ends_with(" 1\n"));
+        drop(stream);
+        assert!(upstreams.status().ends_with(" 0\n"));
+        // 不可用的服务器不再尝试
+        assert_eq!(upstreams.candidates(&target).len(), 1);
+
+        let upstreams = Upstreams::new("default", vec![closed], Strategy::Failover);
+        assert!(upstreams.connect(0, &target).await.is_err());
+        // 全部不可用时仍然尝试
+        assert_eq!(upstreams.candidates(&target).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let good = server().await;
+        let upstreams = Upstreams::new("default", vec![good, closed], Strategy::Failover);
+        let probe = Some(Target::Hostname("example.com:80".into()));
+        let job = spawn_check(vec![upstreams.clone()], Duration::from_secs(60), probe, 0, None);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        job.abort();
+        assert!(upstreams.servers[0].is_up());
+        assert!(!upstreams.servers[1].is_up());
+    }
+
+    #[tokio::test]
+    async fn test_strategy() {
+        let (a, b, c) = (server().await, server().await, server().await);
+        let target = |h: &str| Target::Hostname(format!("{}:443", h));
+        let first = |u: &Upstreams, t: &Target| u.candidates(t)[0].addr;
+
+        let upstreams = Upstreams::new("rr", vec![a, b, c], Strategy::RoundRobin);
+        let t = target("example.com");
+        assert_eq!([first(&upstreams, &t), first(&upstreams, &t), first(&upstreams, &t), first(&upstreams, &t)], [a, b, c, a]);
+
+        let upstreams = Upstreams::new("lc", vec![a, b, c], Strategy::LeastConn);
+        let s1 = upstreams.connect(0, &t).await.unwrap();
+        let s2 = upstreams.connect(0, &t).await.unwrap();
+        assert_eq!((s1.peer_addr().unwrap(), s2.peer_addr().unwrap()), (a, b));
+        drop(s1);
+        assert_eq!(first(&upstreams, &t), a);
+
+        // 同一个主机总是选择同一个服务器，服务器不可用时只影响原来选择它的主机，
+        // 哈希值固定，升级或者重启后仍然选择同一个服务器
+        assert_eq!(hash("example.com", "127.0.0.1:1080".parse().unwrap()), 0xa4c0cf6590dcd3b9);
+        let upstreams = Upstreams::new("hash", vec![a, b, c], Strategy::Hash);
+        let hosts: Vec<Target> = (0..32).map(|i| target(&format!("host{}.example.com", i))).collect();
+        let before: Vec<_> = hosts.iter().map(|t| first(&upstreams, t)).collect();
+        assert_eq!(before, hosts.iter().map(|t| first(&upstreams, t)).collect::<Vec<_>>());
+        assert!(before.contains(&a) && before.contains(&b) && before.contains(&c));
+        upstreams.servers[1].update(Err(&anyhow::anyhow!("down")));
+        for (t, addr) in hosts.iter().zip(before) {
+            if addr != b {
+                assert_eq!(first(&upstreams, t), addr);
+            } else {
+                assert_ne!(first(&upstreams, t), b);
+            }
+        }
+    }
+
+    /// 转发连接的跳板代理，http 为 true 时使用 CONNECT 方法，否则为 socks5
+    async fn jump(http: bool) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let target = if http {
+                        let mut head = Vec::new();
+                        while !head.ends_with(b"\r\n\r\n") {
+                            head.push(s.read_u8().await?);
+                        }
+                        let head = String::from_utf8(head)?;
+                        parse_authority(head.split_whitespace().nth(1).unwrap_or_default(), 80)?
+                    } else {
+                        socks::accept(&mut s, &HashMap::new()).await?.1
+                    };
+                    let mut remote = target.connect_fwmark(0).await?;
+                    if http {
+                        s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+                    } else {
+                        s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+                    }
+                    copy_bidirectional(&mut s, &mut remote).await?;
+                    Ok::<_, anyhow::Error>(())
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_chain() {
+        let (good, first, second) = (server().await, jump(false).await, jump(true).await);
+        let target = Target::Hostname("example.com:443".into());
+        let mut upstreams = Upstreams::new("chain", vec![good], Strategy::Failover);
+        let via = vec![format!("socks5://{}", first), format!("http://{}/", second)];
+        upstreams.via = std::sync::Arc::new(Hop::parse_chain(&via).unwrap());
+        let stream = upstreams.connect(0, &target).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), first);
+        assert_eq!(upstreams.via[1].to_string(), format!("http://{}", second));
+
+        assert!(Hop::parse_chain(&["socks5://jump.example.com:1080".to_string()]).is_err());
+        assert!(Hop::parse_chain(&[first.to_string(), "http://jump.example.com".to_string()]).is_ok());
+    }
+}
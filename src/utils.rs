@@ -4,13 +4,13 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use anyhow::anyhow;
-use async_recursion::async_recursion;
 use log::{debug, trace, warn};
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 use prelude::Result;
 
+use crate::http::{RequestHead, ResponseHead};
 use crate::prelude;
 use crate::prelude::Target;
 
@@ -43,7 +43,7 @@ pub fn get_target_address(s: &TcpStream) -> Option<SocketAddr> {
                              p as *mut libc::c_void, &mut size as *mut libc::socklen_t)
         };
         if r == 0 {
-            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::from((data.sin_addr.s_addr as u32).to_be())), (data.sin_port as u16).to_be());
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(data.sin_addr.s_addr.to_be())), data.sin_port.to_be());
             return Some(addr);
         }
     } else {
@@ -62,12 +62,12 @@ pub fn get_target_address(s: &TcpStream) -> Option<SocketAddr> {
         };
         if r == 0 {
             let ip = Ipv6Addr::from(u128::from_be_bytes(data.sin6_addr.s6_addr));
-            let addr = SocketAddr::new(IpAddr::V6(ip), (data.sin6_port as u16).to_be());
+            let addr = SocketAddr::new(IpAddr::V6(ip), data.sin6_port.to_be());
             return Some(addr);
         }
     }
     debug!("syscall error:{}",Error::last_os_error());
-    return None;
+    None
 }
 
 #[cfg(not(unix))]
@@ -76,66 +76,318 @@ pub fn get_target_address(s: &TcpStream) -> Option<SocketAddr> {
     None
 }
 
-pub fn get_https_domain(buf: &[u8]) -> Result<Target> {
-    let max = buf.len();
-    if max < 43 {
-        return Err(anyhow!("packet length is too short"));
-    }
-    if buf[0] != 0x16 || buf[1] != 0x03 {
-        return Err(anyhow!("this is not a valid tls packet"));
-    }
-    let mut i: usize = 43;
-    i += 1 + buf[i] as usize;
-    if i >= max {
-        return Err(anyhow!("corrupted data package"));
-    }
-    i += 2 + ((buf[i] as usize) << 8 | (buf[i + 1] as usize));
-    if i >= max {
-        return Err(anyhow!("corrupted data package"));
-    }
-    i += 1 + (buf[i] as usize);
-    if i >= max {
-        return Err(anyhow!("corrupted data package"));
-    }
-    let end: usize = i + 2 + ((buf[i] as usize) << 8 | (buf[i + 1] as usize)); // extensions end
-    i += 2;                                   // extensions start
-    if i >= max {
-        return Err(anyhow!("corrupted data package"));
-    }
-    while i <= end {
-        if i == end {
-            return Err(anyhow!("https request hostname not found"));
-        } else if i > end {
-            return Err(anyhow!("packet is corrupted"));
+/// ClientHello 握手消息的最大长度，超过这个长度不再继续读取
+pub const MAX_CLIENT_HELLO: usize = 16 * 1024;
+
+/// HTTP 请求头的默认最大长度
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// 缓冲区的初始大小，请求头超过这个长度时按倍数扩大
+const BUFFER_SIZE: usize = 4096;
+
+/// 从客户端读取数据直到 raw 中包含完整的 ClientHello 握手消息，ClientHello 可能跨越多个 TLS 记录和 TCP 分段。
+/// 所有读取到的原始数据都会保存在 raw 中，无论是否读取成功，调用方都需要将这些数据转发给目标服务器。
+pub async fn read_client_hello<R>(r: &mut R, raw: &mut Vec<u8>) -> Result<()> where R: AsyncRead + Unpin {
+    loop {
+        if reassemble_handshake(raw)?.is_some() {
+            return Ok(());
         }
-        if buf[i] == 0x00 && buf[i + 1] == 0x00 {
-            let next: usize = i + 4 + ((buf[i + 2] as usize) << 8 | (buf[i + 3] as usize));
-            if buf[i + 6] != 0x00 {
-                return Err(anyhow!("unknown address type:{}", buf[i + 6]));
+        if raw.len() >= MAX_CLIENT_HELLO + 1024 {
+            return Err(anyhow!("client hello is too large"));
+        }
+        raw.reserve(2048);
+        if r.read_buf(raw).await? == 0 {
+            return Err(anyhow!("connection has been closed"));
+        }
+    }
+}
+
+/// 从 TLS 记录中重组第一个握手消息，数据不完整时返回 None
+pub fn reassemble_handshake(buf: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut handshake: Vec<u8> = Vec::new();
+    let mut i: usize = 0;
+    loop {
+        if handshake.len() >= 4 {
+            if handshake[0] != 0x01 {
+                return Err(anyhow!("this is not a client hello message"));
             }
-            let name_length: usize = (buf[i + 7] as usize) << 8 | (buf[i + 8] as usize);
-            if i + 9 + name_length != next {
-                return Err(anyhow!("data length mismatch"));
+            let length = 4 + ((handshake[1] as usize) << 16 | (handshake[2] as usize) << 8 | (handshake[3] as usize));
+            if length > MAX_CLIENT_HELLO {
+                return Err(anyhow!("client hello is too large:{}",length));
             }
-            if i + 9 + name_length > end {
-                return Err(anyhow!("data length mismatch"));
+            if handshake.len() >= length {
+                handshake.truncate(length);
+                return Ok(Some(handshake));
             }
-            let hostname = std::str::from_utf8(&buf[i + 9..i + 9 + name_length])?;
-            trace!("https request hostname:{}",hostname);
-            return Ok(Target::Hostname(String::from(hostname)));
         }
-        i += 4 + ((buf[i + 2] as usize) << 8 | (buf[i + 3] as usize))
+        if buf.len() < i + 5 {
+            return Ok(None);
+        }
+        if buf[i] != 0x16 || buf[i + 1] != 0x03 {
+            return Err(anyhow!("this is not a valid tls packet"));
+        }
+        let length = (buf[i + 3] as usize) << 8 | (buf[i + 4] as usize);
+        if length == 0 {
+            return Err(anyhow!("corrupted data package"));
+        }
+        if buf.len() < i + 5 + length {
+            return Ok(None);
+        }
+        handshake.extend_from_slice(&buf[i + 5..i + 5 + length]);
+        i += 5 + length;
     }
-    return Err(anyhow!("https request hostname not found"));
 }
 
-pub async fn get_http_domain(client: &mut TcpStream) -> Result<(Buffer, Target)> {
-    let mut buf = Buffer {
-        data: [0u8; 4096],
-        pos: 0,
-        cap: 0,
-    };
-    let dst = get_target_address(&client); // http
+/// 从 TLS 记录中解析 SNI
+pub fn get_https_domain(buf: &[u8]) -> Result<Target> {
+    match get_client_hello(buf)?.sni {
+        Some(hostname) => Ok(Target::Hostname(hostname)),
+        None => Err(anyhow!("https request hostname not found")),
+    }
+}
+
+/// 从 TLS 记录中解析 ClientHello
+pub fn get_client_hello(buf: &[u8]) -> Result<ClientHelloInfo> {
+    match reassemble_handshake(buf)? {
+        Some(hello) => parse_client_hello(&hello),
+        None => Err(anyhow!("packet length is too short")),
+    }
+}
+
+/// 带边界检查的读取器，数据不足时返回错误，避免畸形数据导致越界 panic
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+    /// 已经读取的字节数
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let data = self.pos.checked_add(n).and_then(|end| self.buf.get(self.pos..end))
+            .ok_or(anyhow!("corrupted data package"))?;
+        self.pos += n;
+        Ok(data)
+    }
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok((b[0] as u16) << 8 | (b[1] as u16))
+    }
+    /// 一个字节长度前缀的数据
+    pub fn vec8(&mut self) -> Result<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.bytes(n)
+    }
+    /// 两个字节长度前缀的数据
+    pub fn vec16(&mut self) -> Result<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.bytes(n)
+    }
+    /// QUIC 变长整数，RFC 9000 16
+    pub fn varint(&mut self) -> Result<u64> {
+        let first = self.u8()?;
+        let n = 1usize << (first >> 6);
+        let mut v = (first & 0x3f) as u64;
+        for b in self.bytes(n - 1)? {
+            v = v << 8 | *b as u64;
+        }
+        Ok(v)
+    }
+}
+
+/// ClientHello 中可用于路由和日志的信息
+#[derive(Debug, Default, Clone)]
+pub struct ClientHelloInfo {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    /// legacy_version 字段
+    pub version: u16,
+    pub supported_versions: Vec<u16>,
+    pub cipher_suites: Vec<u16>,
+    /// 按出现顺序排列的扩展类型
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    /// 是否包含 encrypted_client_hello 扩展，此时 sni 可能是外层的公开名称而不是真实的目标。
+    /// 浏览器在没有 ECH 配置时也会发送 GREASE ECH 扩展，两者的格式相同，需要用 is_ech 按外层 SNI 区分
+    pub ech: bool,
+}
+
+/// GREASE 值，RFC 8701，计算指纹时需要忽略
+#[inline]
+fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+fn u16_list(data: &[u8]) -> Result<Vec<u16>> {
+    let mut r = Reader::new(data);
+    let mut list = Vec::with_capacity(data.len() / 2);
+    while !r.is_empty() {
+        list.push(r.u16()?);
+    }
+    Ok(list)
+}
+
+fn join<T: ToString>(list: &[T], sep: &str) -> String {
+    list.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(sep)
+}
+
+/// sha256 的前 12 个十六进制字符
+fn hash12(data: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data.as_bytes());
+    digest.as_ref()[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ClientHelloInfo {
+    /// 根据外层 SNI 判断是否为真正的 ECH：public_names 为空时所有带 ECH 扩展的连接都算，
+    /// 否则外层 SNI 必须是其中之一或者它们的子域名，用于排除浏览器发送的 GREASE ECH
+    pub fn is_ech(&self, public_names: &[String]) -> bool {
+        if !self.ech {
+            return false;
+        }
+        if public_names.is_empty() {
+            return true;
+        }
+        let Some(sni) = self.sni.as_deref() else { return true; };
+        public_names.iter().any(|n| sni == n || sni.strip_suffix(n.as_str()).is_some_and(|p| p.ends_with('.')))
+    }
+    /// JA3 指纹原始字符串
+    pub fn ja3_string(&self) -> String {
+        let filter = |list: &[u16]| -> Vec<u16> { list.iter().cloned().filter(|v| !is_grease(*v)).collect() };
+        format!("{},{},{},{},{}",
+                self.version,
+                join(&filter(&self.cipher_suites), "-"),
+                join(&filter(&self.extensions), "-"),
+                join(&filter(&self.supported_groups), "-"),
+                join(&self.ec_point_formats, "-"))
+    }
+    /// JA3 指纹，JA3 原始字符串的 md5
+    pub fn ja3(&self) -> String {
+        format!("{:x}", md5::compute(self.ja3_string()))
+    }
+    /// JA4 指纹，格式为 `t13d1516h2_8daaf6152771_e5627efa2ab1`
+    pub fn ja4(&self) -> String {
+        let version = self.supported_versions.iter().cloned()
+            .filter(|v| !is_grease(*v)).max().unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+        let ciphers: Vec<u16> = self.cipher_suites.iter().cloned().filter(|v| !is_grease(*v)).collect();
+        let extensions: Vec<u16> = self.extensions.iter().cloned().filter(|v| !is_grease(*v)).collect();
+        let alpn = match self.alpn.first().map(|a| a.as_bytes()) {
+            Some([first, .., last]) | Some([first @ last]) => {
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", *first as char, *last as char)
+                } else {
+                    let hex = format!("{:02x}{:02x}", first, last);
+                    format!("{}{}", &hex[..1], &hex[3..])
+                }
+            }
+            _ => "00".to_string(),
+        };
+        let a = format!("t{}{}{:02}{:02}{}",
+                        version,
+                        if self.sni.is_some() { 'd' } else { 'i' },
+                        ciphers.len().min(99),
+                        extensions.len().min(99),
+                        alpn);
+        let hex = |list: &[u16]| -> String {
+            list.iter().map(|v| format!("{:04x}", v)).collect::<Vec<String>>().join(",")
+        };
+        let mut sorted = ciphers.clone();
+        sorted.sort();
+        let b = if sorted.is_empty() { "000000000000".to_string() } else { hash12(&hex(&sorted)) };
+        let mut sorted: Vec<u16> = extensions.iter().cloned().filter(|v| *v != 0x0000 && *v != 0x0010).collect();
+        sorted.sort();
+        let c = if sorted.is_empty() {
+            "000000000000".to_string()
+        } else if self.signature_algorithms.is_empty() {
+            hash12(&hex(&sorted))
+        } else {
+            hash12(&format!("{}_{}", hex(&sorted), hex(&self.signature_algorithms)))
+        };
+        format!("{}_{}_{}", a, b, c)
+    }
+}
+
+/// 解析完整的 ClientHello 握手消息
+pub fn parse_client_hello(buf: &[u8]) -> Result<ClientHelloInfo> {
+    let mut info = ClientHelloInfo::default();
+    let mut r = Reader::new(buf);
+    r.bytes(4)?;       // 握手消息类型和长度
+    info.version = r.u16()?;
+    r.bytes(32)?;      // 随机数
+    r.vec8()?;         // session id
+    info.cipher_suites = u16_list(r.vec16()?)?;
+    r.vec8()?;         // compression methods
+    if r.is_empty() {
+        return Ok(info);
+    }
+    let mut extensions = Reader::new(r.vec16()?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        info.extensions.push(extension_type);
+        match extension_type {
+            0x0000 => info.sni = get_server_name(data)?,
+            0x000a => info.supported_groups = u16_list(Reader::new(data).vec16()?)?,
+            0x000b => info.ec_point_formats = Reader::new(data).vec8()?.to_vec(),
+            0x000d => info.signature_algorithms = u16_list(Reader::new(data).vec16()?)?,
+            0x0010 => {
+                let mut list = Reader::new(Reader::new(data).vec16()?);
+                while !list.is_empty() {
+                    info.alpn.push(String::from_utf8_lossy(list.vec8()?).to_string());
+                }
+            }
+            0x002b => info.supported_versions = u16_list(Reader::new(data).vec8()?)?,
+            0xfe0d => info.ech = true,
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// 解析 server_name 扩展，RFC 6066 3
+fn get_server_name(data: &[u8]) -> Result<Option<String>> {
+    let mut list = Reader::new(Reader::new(data).vec16()?);
+    while !list.is_empty() {
+        let name_type = list.u8()?;
+        let name = list.vec16()?;
+        if name_type != 0x00 { // 只处理 host_name 类型
+            trace!("unknown address type:{}", name_type);
+            continue;
+        }
+        if name.is_empty() {
+            return Err(anyhow!("data length mismatch"));
+        }
+        let hostname = std::str::from_utf8(name)?;
+        trace!("https request hostname:{}",hostname);
+        return Ok(Some(String::from(hostname)));
+    }
+    Ok(None)
+}
+
+/// 读取第一个 HTTP 请求头，返回缓冲区、目标地址和解析出的请求头。
+/// 请求头无法解析或者超过 max_header_size 时使用原始目标地址，此时请求头为 None
+pub async fn get_http_domain(client: &mut TcpStream, dst: Option<SocketAddr>, max_header_size: usize) -> Result<(Buffer, Target, Option<RequestHead>)> {
+    let mut buf = Buffer::new(max_header_size);
     let port = match dst {
         Some(addr) => {
             trace!("target address:{}",addr);
@@ -143,31 +395,16 @@ pub async fn get_http_domain(client: &mut TcpStream) -> Result<(Buffer, Target)>
         }
         None => 80
     };
-    let _ = buf.read_line(client).await?;// 读取第一行
-    loop {
-        match buf.read_line(client).await {
-            Ok(line) => {
-                let (k, v) = sp(line);
-                if k == "Host" {
-                    // 如果 http 请求已经携带端口，使用请求头里面的端口信息
-                    // 如果没有则尝试获取源请求端口
-                    if v.contains(":") {
-                        return Ok((buf, Target::Hostname(v)));
-                    }
-                    return Ok((buf, Target::Hostname(v).set_port(port)));
-                }
-            }
-            Err(err) => {
-                return match dst {
-                    Some(addr) => {
-                        Ok((buf, addr.into()))
-                    }
-                    None => {
-                        Err(anyhow!("unable to get http request hostname: {}",err))
-                    }
-                };
-            }
-        }
+    // 请求中没有端口时使用原始目标端口
+    let head = buf.read_head(client).await.and_then(|head| {
+        trace!("http request: {} {} {}",head.method,head.uri,head.version);
+        let target = head.target(port)?;
+        Ok((head, target))
+    });
+    match (head, dst) {
+        (Ok((head, target)), _) => Ok((buf, target, Some(head))),
+        (Err(_), Some(addr)) => Ok((buf, addr.into(), None)),
+        (Err(err), None) => Err(anyhow!("unable to get http request hostname: {}",err)),
     }
 }
 
@@ -180,11 +417,11 @@ impl Target {
             }
             Target::IPv4(mut ip) => {
                 ip.set_port(p);
-                self
+                Target::IPv4(ip)
             }
             Target::IPv6(mut ip) => {
                 ip.set_port(p);
-                self
+                Target::IPv6(ip)
             }
         }
     }
@@ -196,13 +433,13 @@ pub fn just_hostname(hostname: String) -> String {
         let (hostname, _) = hostname.split_once(":").unwrap();
         return String::from(hostname);
     }
-    return hostname;
+    hostname
 }
 
-pub async fn combine(mut client: TcpStream, mut target: TcpStream) {
+pub async fn combine<S>(mut client: TcpStream, target: S) where S: AsyncRead + AsyncWrite {
     // connect to the target
     let (mut r1, mut w1) = client.split();
-    let (mut r2, mut w2) = target.split();
+    let (mut r2, mut w2) = tokio::io::split(target);
     let (n1, n2) = tokio::join!(tokio::io::copy(&mut r1, &mut w2), tokio::io::copy(&mut r2, &mut w1));
     debug!(
         "=> send: {} bytes, receive:{} bytes",
@@ -212,41 +449,120 @@ pub async fn combine(mut client: TcpStream, mut target: TcpStream) {
 }
 
 pub struct Buffer {
-    data: [u8; 4096],
+    data: Vec<u8>,
     pos: usize,
     cap: usize,
+    /// 缓冲区的最大长度，也就是请求头的最大长度
+    max: usize,
 }
 
 impl Buffer {
+    pub fn new(max: usize) -> Self {
+        Buffer { data: vec![0u8; BUFFER_SIZE.min(max)], pos: 0, cap: 0, max }
+    }
     pub fn bytes(&self) -> &[u8] {
         &self.data[..self.cap]
     }
-    #[async_recursion]
+    /// 已经读取过的数据
+    pub fn head(&self) -> &[u8] {
+        &self.data[..self.pos]
+    }
+    /// 已经收到但还没有读取的数据
+    pub fn pending(&self) -> &[u8] {
+        &self.data[self.pos..self.cap]
+    }
+    /// 丢弃已经读取过的数据
+    pub fn consume(&mut self) {
+        self.data.copy_within(self.pos..self.cap, 0);
+        self.cap -= self.pos;
+        self.pos = 0;
+    }
+    /// 从当前位置读取一个完整的请求头
+    pub async fn read_head<R>(&mut self, r: &mut R) -> Result<RequestHead> where R: AsyncRead + Send + Sync + Unpin {
+        let start = self.pos;
+        while !self.read_line(r).await?.is_empty() {}
+        match RequestHead::parse(&self.data[start..self.pos])? {
+            Some((head, _)) => Ok(head),
+            None => Err(anyhow!("incomplete http request")),
+        }
+    }
+    /// 从当前位置读取一个完整的响应头
+    pub async fn read_response<R>(&mut self, r: &mut R) -> Result<ResponseHead> where R: AsyncRead + Send + Sync + Unpin {
+        let start = self.pos;
+        while !self.read_line(r).await?.is_empty() {}
+        match ResponseHead::parse(&self.data[start..self.pos])? {
+            Some((head, _)) => Ok(head),
+            None => Err(anyhow!("incomplete http response")),
+        }
+    }
+    /// 将 n 字节的数据从 r 复制到 w，优先使用缓冲区中的数据
+    pub async fn copy_exact<R, W>(&mut self, n: u64, r: &mut R, w: &mut W) -> Result<()>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+        let buffered = (self.cap - self.pos).min(n.try_into().unwrap_or(usize::MAX));
+        w.write_all(&self.data[self.pos..self.pos + buffered]).await?;
+        self.pos += buffered;
+        let rest = n - buffered as u64;
+        if rest > 0 && tokio::io::copy(&mut r.take(rest), w).await? != rest {
+            return Err(anyhow!("connection has been closed"));
+        }
+        Ok(())
+    }
+    /// 复制 chunked 编码的请求体，包括最后的 trailer
+    pub async fn copy_chunked<R, W>(&mut self, r: &mut R, w: &mut W) -> Result<()>
+        where R: AsyncRead + Send + Sync + Unpin, W: AsyncWrite + Unpin {
+        loop {
+            self.consume();
+            let line = self.read_line(r).await?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| anyhow!("invalid chunk size: {}",line))?;
+            w.write_all(self.head()).await?;
+            if size == 0 {
+                loop {
+                    self.consume();
+                    let end = self.read_line(r).await?.is_empty();
+                    w.write_all(self.head()).await?;
+                    if end {
+                        return Ok(());
+                    }
+                }
+            }
+            // 数据后面的 CRLF
+            self.copy_exact(size + 2, r, w).await?;
+        }
+    }
     async fn read_line<R>(&mut self, r: &mut R) -> Result<&str> where R: AsyncRead + Send + Sync + Unpin {
-        for i in self.pos..self.cap {
-            if self.data[i] == '\n' as u8 {
+        // 只检查新读取的数据，避免一行很长时重复扫描
+        let mut scan = self.pos;
+        loop {
+            if let Some(n) = self.data[scan..self.cap].iter().position(|c| *c == b'\n') {
+                let i = scan + n;
                 let start = self.pos;
-                let end = if i > 0 && self.data[i - 1] == '\r' as u8 {
+                let end = if i > 0 && self.data[i - 1] == b'\r' {
                     i - 1
                 } else { i };
                 self.pos = i + 1;
                 let row: &str = std::str::from_utf8(&self.data[start..end])?;
                 return Ok(row);
             }
+            scan = self.cap;
+            if self.cap >= self.data.len() {
+                if self.data.len() >= self.max {
+                    return Err(anyhow!("data length exceeded:{}",self.max));
+                }
+                let size = (self.data.len() * 2).clamp(1, self.max);
+                self.data.resize(size, 0);
+            }
+            let n = r.read(&mut self.data[self.cap..]).await?;
+            if n == 0 {
+                return Err(anyhow!("connection has been closed"));
+            }
+            self.cap += n;
         }
-        if self.cap >= self.data.len() {
-            return Err(anyhow!("data length exceeded:{}",self.data.len()));
-        }
-        let n = r.read(&mut self.data[self.pos..]).await?;
-        if n == 0 {
-            return Err(anyhow!("connection has been closed"));
-        }
-        self.cap += n;
-        self.read_line(r).await
     }
 }
 
-fn sp(line: &str) -> (String, String) {
+pub fn sp(line: &str) -> (String, String) {
     match line.split_once(':') {
         Some((k, v)) => (String::from(k), String::from(v.trim())),
         None => (String::from(line), String::new())
@@ -254,11 +570,30 @@ fn sp(line: &str) -> (String, String) {
 }
 
 #[cfg(test)]
-mod tests {
-    use tokio::io::AsyncReadExt;
+pub mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
 
+    use crate::http::Body;
     use crate::prelude::Target;
-    use crate::utils::{Buffer, get_https_domain, sp};
+    use crate::utils::{Buffer, ClientHelloInfo, get_client_hello, get_https_domain, MAX_HEADER_SIZE, read_client_hello, reassemble_handshake, sp};
+
+    const CLIENT_HELLO: [u8; 170] = [0x16u8, 0x03, 0x01, 0x00, 0xa5,
+        0x01, 0x00, 0x00, 0xa1,
+        0x03, 0x03,
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+        0x00,
+        0x00, 0x20, 0xcc, 0xa8, 0xcc, 0xa9, 0xc0, 0x2f, 0xc0, 0x30, 0xc0, 0x2b, 0xc0, 0x2c, 0xc0, 0x13, 0xc0, 0x09, 0xc0, 0x14, 0xc0, 0x0a, 0x00, 0x9c, 0x00, 0x9d, 0x00, 0x2f, 0x00, 0x35, 0xc0, 0x12, 0x00, 0x0a,
+        0x01, 0x00,
+        0x00, 0x58,
+        0x00, 0x00, 0x00, 0x18, 0x00, 0x16, 0x00, 0x00, 0x13, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x75, 0x6c, 0x66, 0x68, 0x65, 0x69, 0x6d, 0x2e, 0x6e, 0x65, 0x74,
+        0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x19,
+        0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+        0x00, 0x0d, 0x00, 0x12, 0x00, 0x10, 0x04, 0x01, 0x04, 0x03, 0x05, 0x01, 0x05, 0x03, 0x06, 0x01, 0x06, 0x03, 0x02, 0x01, 0x02, 0x03,
+        0xff, 0x01, 0x00, 0x01, 0x00,
+        0x00, 0x12, 0x00, 0x00];
 
     #[test]
     fn start() {
@@ -285,10 +620,125 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_client_hello_info() {
+        let info = get_client_hello(&CLIENT_HELLO).unwrap();
+        assert_eq!(info.sni.as_deref(), Some("example.ulfheim.net"));
+        assert_eq!(info.version, 0x0303);
+        assert_eq!(info.cipher_suites.len(), 16);
+        assert_eq!(info.extensions, vec![0x0000, 0x0005, 0x000a, 0x000b, 0x000d, 0xff01, 0x0012]);
+        assert_eq!(info.supported_groups, vec![0x001d, 0x0017, 0x0018, 0x0019]);
+        assert!(info.alpn.is_empty());
+        assert_eq!(info.ja3_string(), "771,52392-52393-49199-49200-49195-49196-49171-49161-49172-49162-156-157-47-53-49170-10,0-5-10-11-13-65281-18,29-23-24-25,0");
+        assert_eq!(info.ja3(), "36d715579d31b7f031149c4560b5914f");
+        assert_eq!(info.ja4(), "t12d160700_8cdfa2d4673b_18dd7303c4a5");
+
+        let info = ClientHelloInfo {
+            sni: None,
+            alpn: vec!["h2".into(), "http/1.1".into()],
+            version: 0x0303,
+            supported_versions: vec![0x0a0a, 0x0304, 0x0303],
+            cipher_suites: vec![0x1a1a, 0x1301],
+            extensions: vec![0x2a2a, 0x0010, 0x002b],
+            ..Default::default()
+        };
+        assert!(info.ja4().starts_with("t13i0102h2_"));
+        assert_eq!(info.ja3_string(), "771,4865,16-43,,");
+    }
+
+    /// 使用给定的扩展构造一个 TLS 记录
+    pub fn build_client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        let mut ext = Vec::new();
+        for (t, data) in extensions {
+            ext.extend_from_slice(&t.to_be_bytes());
+            ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            ext.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+        let mut handshake = vec![0x01, 0x00, (body.len() >> 8) as u8, body.len() as u8];
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01, (handshake.len() >> 8) as u8, handshake.len() as u8];
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    /// server_name 扩展
+    pub fn server_name(hostname: &str) -> (u16, Vec<u8>) {
+        let n = hostname.len();
+        let mut data = vec![0x00, (n + 3) as u8, 0x00, 0x00, n as u8];
+        data.extend_from_slice(hostname.as_bytes());
+        (0x0000, data)
+    }
+
+    #[test]
+    fn test_encrypted_client_hello() {
+        let ech = (0xfe0d, vec![0x00, 0x00, 0x01, 0x00, 0x01, 0x2a, 0x00, 0x00, 0x00, 0x01, 0xff]);
+        let info = get_client_hello(&build_client_hello(&[server_name("cloudflare-ech.com"), ech])).unwrap();
+        assert!(info.ech);
+        assert_eq!(info.sni.as_deref(), Some("cloudflare-ech.com"));
+        let names = vec!["cloudflare-ech.com".to_string()];
+        assert!(info.is_ech(&names));
+        // GREASE ECH，外层 SNI 是真实的目标
+        let grease = (0xfe0d, vec![0x00, 0x00, 0x01, 0x00, 0x01, 0x2a, 0x00, 0x00, 0x00, 0x01, 0xff]);
+        let info = get_client_hello(&build_client_hello(&[server_name("www.example.com"), grease])).unwrap();
+        assert!(!info.is_ech(&names));
+        assert!(info.is_ech(&[]));
+        let info = get_client_hello(&build_client_hello(&[server_name("example.com")])).unwrap();
+        assert!(!info.ech);
+        assert!(!info.is_ech(&[]));
+        assert_eq!(info.cipher_suites, vec![0x1301]);
+    }
+
+    /// 将 ClientHello 拆分成两个 TLS 记录
+    fn fragmented_hello() -> Vec<u8> {
+        let handshake = &CLIENT_HELLO[5..];
+        let mut data = vec![0x16, 0x03, 0x01, 0x00, 0x40];
+        data.extend_from_slice(&handshake[..0x40]);
+        data.extend_from_slice(&[0x16, 0x03, 0x01, 0x00, (handshake.len() - 0x40) as u8]);
+        data.extend_from_slice(&handshake[0x40..]);
+        data
+    }
+
+    #[test]
+    fn test_fragmented_client_hello() {
+        let data = fragmented_hello();
+        assert!(matches!(get_https_domain(&data).unwrap(), Target::Hostname(h) if h == "example.ulfheim.net"));
+        assert!(reassemble_handshake(&data[..0x45]).unwrap().is_none());
+        assert!(reassemble_handshake(&data[..data.len() - 1]).unwrap().is_none());
+        assert!(get_https_domain(&data[..0x50]).is_err());
+        assert!(reassemble_handshake(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let data = fragmented_hello();
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let segments = data.clone();
+        tokio::spawn(async move {
+            for segment in segments.chunks(30) {
+                client.write_all(segment).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            client.write_all(b"early data").await.unwrap();
+        });
+        let mut raw = Vec::new();
+        read_client_hello(&mut server, &mut raw).await.unwrap();
+        assert!(raw.starts_with(&data));
+        assert!(matches!(get_https_domain(&raw).unwrap(), Target::Hostname(h) if h == "example.ulfheim.net"));
+
+        let mut raw = Vec::new();
+        assert!(read_client_hello(&mut &data[..0x50], &mut raw).await.is_err());
+        assert_eq!(raw, &data[..0x50]);
+    }
+
     #[tokio::test]
     async fn test_buffer_read_line() {
         let data = "hello\nworld\r\n".as_bytes().to_vec();
-        let mut buffer = Buffer { data: [0u8; 4096], cap: 11, pos: 0 };
+        let mut buffer = Buffer::new(MAX_HEADER_SIZE);
         let mut reader = &data[..];
         let line1 = buffer.read_line(&mut reader).await.unwrap();
         assert_eq!(line1, "hello");
@@ -298,6 +748,61 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_buffer_large_header() {
+        let data = format!("GET / HTTP/1.1\r\nHost: a.com\r\nCookie: {}\r\n\r\n", "x".repeat(20000));
+        let mut buf = Buffer::new(MAX_HEADER_SIZE);
+        let head = buf.read_head(&mut data.as_bytes()).await.unwrap();
+        assert_eq!(head.header("cookie").unwrap().len(), 20000);
+        assert_eq!(buf.head(), data.as_bytes());
+        let mut buf = Buffer::new(16 * 1024);
+        let err = buf.read_head(&mut data.as_bytes()).await.unwrap_err();
+        assert!(err.to_string().contains("exceeded"));
+        assert_eq!(buf.bytes().len(), 16 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_long_line_slowly() {
+        // 每次只能读到一个字节，很长的一行也不会导致栈溢出
+        let data = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(60000));
+        let (mut client, mut server) = tokio::io::duplex(1);
+        let len = data.len();
+        tokio::spawn(async move { client.write_all(data.as_bytes()).await });
+        let mut buf = Buffer::new(MAX_HEADER_SIZE);
+        let head = buf.read_head(&mut server).await.unwrap();
+        assert_eq!(head.header("cookie").unwrap().len(), 60000);
+        assert_eq!(buf.head().len(), len);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_pipelined_requests() {
+        let data = concat!(
+            "POST /a HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n\r\nhello",
+            "POST /b HTTP/1.1\r\nHost: b.com\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "3;ext=1\r\nabc\r\n0\r\nX-Trailer: 1\r\n\r\n",
+            "GET /c HTTP/1.1\r\nHost: a.com\r\n\r\n",
+        );
+        let (mut client, mut server) = tokio::io::duplex(7);
+        tokio::spawn(async move { client.write_all(data.as_bytes()).await });
+        let mut buf = Buffer::new(MAX_HEADER_SIZE);
+        let mut out = Vec::new();
+        let mut hosts = Vec::new();
+        for _ in 0..3 {
+            let head = buf.read_head(&mut server).await.unwrap();
+            hosts.push(head.target(80).unwrap().to_string());
+            out.extend_from_slice(buf.head());
+            match head.body().unwrap() {
+                Body::Length(n) => buf.copy_exact(n, &mut server, &mut out).await.unwrap(),
+                Body::Chunked => buf.copy_chunked(&mut server, &mut out).await.unwrap(),
+                Body::Tunnel => unreachable!(),
+            }
+            buf.consume();
+        }
+        assert_eq!(hosts, ["a.com:80", "b.com:80", "a.com:80"]);
+        assert_eq!(std::str::from_utf8(&out).unwrap(), data);
+        assert!(buf.read_head(&mut server).await.is_err());
+    }
+
     #[test]
     fn test_sp() {
         let line1 = "key:value";
@@ -313,5 +818,72 @@ mod tests {
         assert_eq!(k3, "key");
         assert_eq!(v3, "");
     }
-}
 
+    mod fuzz {
+        use proptest::prelude::*;
+        use tokio::io::AsyncWriteExt;
+
+        use crate::http::RequestHead;
+        use crate::utils::{Buffer, get_https_domain, reassemble_handshake, sp};
+        use crate::utils::tests::CLIENT_HELLO;
+
+        /// 模拟 get_http_domain 的读取过程，数据按 chunk 大小分段到达
+        fn parse_http(data: &[u8], chunk: usize, max: usize) {
+            let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            rt.block_on(async {
+                let (mut client, mut server) = tokio::io::duplex(chunk);
+                let data = data.to_vec();
+                tokio::spawn(async move {
+                    let _ = client.write_all(&data).await;
+                });
+                let mut buf = Buffer::new(max);
+                while let Ok(line) = buf.read_line(&mut server).await {
+                    let _ = sp(line);
+                }
+                if let Ok(Some((head, _))) = RequestHead::parse(buf.bytes()) {
+                    let _ = head.target(80);
+                }
+            });
+        }
+
+        proptest! {
+            #[test]
+            fn https_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+                let _ = get_https_domain(&data);
+            }
+
+            #[test]
+            fn https_arbitrary_handshake(body in proptest::collection::vec(any::<u8>(), 0..1024)) {
+                let mut data = vec![0x16, 0x03, 0x01, (body.len() >> 8) as u8, body.len() as u8];
+                data.extend_from_slice(&body);
+                let _ = get_https_domain(&data);
+                let _ = reassemble_handshake(&data);
+            }
+
+            #[test]
+            fn https_mutated_client_hello(index in 0..CLIENT_HELLO.len(), value in any::<u8>(), len in 0..=CLIENT_HELLO.len()) {
+                let mut data = CLIENT_HELLO.to_vec();
+                data[index] = value;
+                let _ = get_https_domain(&data[..len]);
+                let _ = get_https_domain(&data);
+            }
+
+            #[test]
+            fn http_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..8192), chunk in 1..512usize, max in 1..16384usize) {
+                parse_http(&data, chunk, max);
+            }
+
+            #[test]
+            fn http_arbitrary_lines(lines in proptest::collection::vec("[ -~]{0,256}", 0..64), chunk in 1..512usize, max in 1..16384usize) {
+                parse_http(lines.join("\r\n").as_bytes(), chunk, max);
+            }
+        }
+
+        #[test]
+        fn https_truncated_client_hello() {
+            for len in 0..CLIENT_HELLO.len() {
+                assert!(get_https_domain(&CLIENT_HELLO[..len]).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#!/bin/sh
+
+ip rule del fwmark 1 lookup 100 2>/dev/null
+ip route flush table 100 2>/dev/null
+ip -6 rule del fwmark 1 lookup 100 2>/dev/null
+ip -6 route flush table 100 2>/dev/null
+
+exec nft -f - <<'NFT'
+table inet https.tproxy {
+}
+delete table inet https.tproxy
+NFT
@@ -0,0 +1,57 @@
+#!/bin/sh
+# TPROXY 模式的规则，配合 harmony-rs --tproxy 使用
+# 被标记为 1 的数据包通过策略路由交给本机，再由 tproxy 规则转发到 harmony-rs 的透明代理端口
+
+ip rule del fwmark 1 lookup 100 2>/dev/null
+ip -6 rule del fwmark 1 lookup 100 2>/dev/null
+ip rule add fwmark 1 lookup 100
+ip route replace local 0.0.0.0/0 dev lo table 100
+ip -6 rule add fwmark 1 lookup 100
+ip -6 route replace local ::/0 dev lo table 100
+
+exec nft -f - <<'NFT'
+table inet https.tproxy {
+}
+delete table inet https.tproxy
+table inet https.tproxy {
+    # 代理域名解析出的地址，由 harmony-rs 的 nftset 配置写入，可用于策略路由
+    set proxy4 {
+        type ipv4_addr; flags timeout;
+    }
+
+    set proxy6 {
+        type ipv6_addr; flags timeout;
+    }
+
+    set reserved4 {
+        type ipv4_addr; flags interval;
+        elements = { 0.0.0.0/8, 127.0.0.0/8, 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16, 224.0.0.0/4, 240.0.0.0/4 }
+    }
+
+    set reserved6 {
+        type ipv6_addr; flags interval;
+        elements = { ::1/128, fc00::/7, fe80::/10, ff00::/8 }
+    }
+
+    chain prerouting {
+        type filter hook prerouting priority mangle; policy accept;
+        ip daddr @reserved4 return
+        ip6 daddr @reserved6 return
+        tcp dport 443 tproxy to :8443 meta mark set 1 accept
+        tcp dport 80 tproxy to :8080 meta mark set 1 accept
+        # QUIC，需要 harmony-rs --udp-port 8443
+        udp dport 443 tproxy to :8443 meta mark set 1 accept
+    }
+
+    # 本机发出的流量，标记后经过策略路由重新进入 prerouting
+    chain output {
+        type route hook output priority mangle; policy accept;
+        meta mark 8366 return
+        skuid nobody skgid nobody return
+        ip daddr @reserved4 return
+        ip6 daddr @reserved6 return
+        tcp dport { 80, 443 } meta mark set 1
+        udp dport 443 meta mark set 1
+    }
+}
+NFT